@@ -0,0 +1,43 @@
+//! Derive macros for the `lockfree` crate.
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields};
+
+/// Implement `lockfree::intrusive::IntrusiveLink` for a struct. A derive
+/// cannot add fields to the struct it is placed on, so the struct has to
+/// declare the link itself as a field named `next` of type `AtomicPtr<Self>`.
+#[proc_macro_derive(IntrusiveLink)]
+pub fn derive_intrusive_link(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let has_next = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => fields
+                .named
+                .iter()
+                .any(|field| field.ident.as_ref().is_some_and(|ident| ident == "next")),
+            _ => false,
+        },
+        _ => false,
+    };
+
+    if !has_next {
+        return syn::Error::new_spanned(
+            name,
+            "IntrusiveLink requires a struct with a `next: AtomicPtr<Self>` field",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    quote! {
+        impl #impl_generics ::lockfree::intrusive::IntrusiveLink for #name #ty_generics #where_clause {
+            fn link(&self) -> &::std::sync::atomic::AtomicPtr<Self> {
+                &self.next
+            }
+        }
+    }
+    .into()
+}
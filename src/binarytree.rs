@@ -1,94 +1,51 @@
-use std::ops::Deref;
-use std::{
-    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
-    sync::Arc,
-    thread,
-};
-
-/// Part of the [LockFreeBinaryTree]. Used to build the binary tree with
-/// TODO: we need to have a thread-safe reference count somewhere to make sure
-/// that a thread cannot modify the pointers while others are reading them.
-struct Node<T> {
-    pub data: AtomicPtr<T>,
-    pub left: AtomicPtr<Node<T>>,
-    pub right: AtomicPtr<Node<T>>,
-}
-
-impl<T> Node<T> {
-    pub fn leaky<U: Into<Box<T>>>(item: U) -> *mut Self {
-        let left = AtomicPtr::new(std::ptr::null_mut::<Node<T>>());
-        let right = AtomicPtr::new(std::ptr::null_mut::<Node<T>>());
-
-        let raw = Box::into_raw(item.into());
-        let boxed = Box::from(Self {
-            data: AtomicPtr::new(raw),
-            left,
-            right,
-        });
-
-        Box::into_raw(boxed)
-    }
-}
+use std::ops::RangeBounds;
 
-/// structure that guards the immutability lifetime of the contained node. As long an instance of
-/// this guard exists no other threads can modify the node.
-pub struct DerefGuard<'a, T> {
-    phantom: std::marker::PhantomData<&'a T>,
-    node: *const Node<T>,
-}
+use crate::treemap::LockFreeTreeMap;
+pub use crate::treemap::{DerefGuard, InvariantViolation};
 
-impl<'a, T> DerefGuard<'a, T> {
-    fn new(node: *const Node<T>) -> Self {
-        Self {
-            phantom: std::marker::PhantomData::default(),
-            node,
-        }
-    }
+/// Ordered set of items. This is a [LockFreeTreeMap] where every item is a
+/// key without a value.
+pub struct LockFreeBinaryTree<T> {
+    pub(crate) map: LockFreeTreeMap<T, ()>,
 }
 
-impl<'a, T> Deref for DerefGuard<'a, T> {
-    type Target = T;
-
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*(*self.node).data.load(Ordering::Acquire) }
+impl<T: Send + std::cmp::Ord> Default for LockFreeBinaryTree<T> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-pub struct LockFreeBinaryTree<T> {
-    head: AtomicPtr<Node<T>>,
-}
-
 impl<T: Send + std::cmp::Ord> LockFreeBinaryTree<T> {
     pub fn new() -> Self {
         Self {
-            head: AtomicPtr::new(std::ptr::null_mut()),
+            map: LockFreeTreeMap::new(),
         }
     }
 
+    /// Insert the given item into the tree. Duplicates are rejected, use
+    /// [LockFreeBinaryTree::push_allowing_duplicates] to store equal items
+    /// next to each other.
+    ///
+    /// # Returns
+    ///
+    /// false when an equal item is already present, the given item is dropped
+    /// in that case.
     pub fn push<U: Into<Box<T>>>(&self, item: U) -> bool {
-        let new_node = Node::leaky(item);
-        loop {
-            let current = self.head.load(Ordering::Relaxed);
-            if current.is_null() {
-                match self.head.compare_exchange(
-                    current,
-                    new_node,
-                    Ordering::Release,
-                    Ordering::Relaxed,
-                ) {
-                    Ok(_) => break,
-                    //other thread was quicker, proceed with adding it
-                    Err(_) => continue,
-                }
-            } else {
-                unsafe {
-                    let data = (&*new_node).data.load(Ordering::Acquire);
-                    let _found_node = self.relaxed_search(current, &*data);
-                    todo!();
-                }
-            }
-        }
-        return true;
+        self.map.insert_if_absent(*item.into(), ())
+    }
+
+    /// Insert the given item into the tree, even when an equal item is
+    /// already present. This turns the tree into a sorted multiset. Equal
+    /// items always go to the right of each other, [LockFreeBinaryTree::find_all]
+    /// yields them in insertion order.
+    pub fn push_allowing_duplicates<U: Into<Box<T>>>(&self, item: U) {
+        self.map.insert_allowing_duplicates(*item.into(), ())
+    }
+
+    /// Find all items equal to the given item, in the order they were pushed
+    /// with [LockFreeBinaryTree::push_allowing_duplicates].
+    pub fn find_all<'a>(&'a self, item: &'a T) -> impl Iterator<Item = DerefGuard<'a, T>> {
+        self.range_by(move |other| other.cmp(item))
     }
 
     /// Find the given element in the container and return a thread-safe
@@ -102,60 +59,117 @@ impl<T: Send + std::cmp::Ord> LockFreeBinaryTree<T> {
     /// # Returns
     ///
     /// A immutable reference to the found item wrapped in a guard. This guard
-    /// makes sure no writes happen as long as the guard object exists.
-    pub fn find<'a>(&self, item: &T) -> DerefGuard<'a, T> {
-        let (node, _) = self.relaxed_search(self.head.load(Ordering::Acquire), item);
-        DerefGuard::new(node)
+    /// makes sure the item is not freed as long as the guard object exists.
+    ///
+    /// # Panics
+    ///
+    /// When the tree is empty, use [LockFreeBinaryTree::get] to look up an
+    /// item without a fallback.
+    pub fn find<'a>(&'a self, item: &T) -> DerefGuard<'a, T> {
+        let (pair, _) = self
+            .map
+            .closest(item)
+            .expect("cannot find an item in an empty tree");
+        DerefGuard::new(&pair.0)
+    }
+
+    /// Find the item equal to the given item.
+    ///
+    /// # Returns
+    ///
+    /// None when the tree does not contain the item.
+    pub fn get<'a>(&'a self, item: &T) -> Option<DerefGuard<'a, T>> {
+        let pair = self.map.find(item)?;
+        Some(DerefGuard::new(&pair.0))
+    }
+
+    /// Check whether the tree contains an item equal to the given item.
+    pub fn contains(&self, item: &T) -> bool {
+        self.get(item).is_some()
     }
 
-    pub fn delete(&self, item: &T) -> bool {
+    pub fn delete(&self, _item: &T) -> bool {
         todo!()
     }
 
-    /// This function walks over the tree to find the node that is closest to the
-    /// given value. Note: The tree can get modified while we are walking over it. 
+    /// Iterate in ascending order over all items that fall inside the given
+    /// range. Items pushed while iterating may or may not be yielded.
     ///
     /// # Arguments
     ///
-    /// * 'current' the root node to start searching with. Cannot be null!
-    /// * 'value' the value to find
+    /// * 'range'   bounds of the items to yield
     ///
-    /// # Return
+    /// # Returns
     ///
-    /// Pointer to the value closest to the given value.
-    fn relaxed_search(&self, current: *const Node<T>, to_find: &T) -> (*const Node<T>, std::cmp::Ordering) {
-        assert!(!current.is_null());
-
-        unsafe {
-            let node_data = (*current).data.load(Ordering::Acquire);
-            // data pointers cannot be invalid!
-            assert!(!node_data.is_null());
-
-            let ordering = (&*node_data).cmp(to_find);
-            let new_current = match ordering {
-                std::cmp::Ordering::Less => (*current).left.load(Ordering::Relaxed),
-                std::cmp::Ordering::Greater => (*current).right.load(Ordering::Relaxed),
-                std::cmp::Ordering::Equal => std::ptr::null_mut(),
-            };
-
-            if new_current.is_null() {
-                return (current, ordering);
-            }
-
-        (new_current, ordering)
+    /// A [RangeIter] yielding a guard for every item inside the range.
+    pub fn range<'a, R: RangeBounds<T> + 'a>(&'a self, range: R) -> RangeIter<'a, T> {
+        RangeIter {
+            inner: self.map.range(range),
         }
     }
+
+    /// Same as [LockFreeBinaryTree::range], but the range is described by a
+    /// function that tells where an item lies relative to the range: `Less`
+    /// when it is below, `Equal` when it is inside and `Greater` when it is
+    /// above the range. The function must be monotonic over the ordering of
+    /// `T`.
+    pub(crate) fn range_by<'a, F>(&'a self, position: F) -> RangeIter<'a, T>
+    where
+        F: Fn(&T) -> std::cmp::Ordering + 'a,
+    {
+        RangeIter {
+            inner: self.map.range_by(position),
+        }
+    }
+
+    /// See [LockFreeTreeMap::verify_invariants].
+    pub fn verify_invariants(&self) -> Result<(), InvariantViolation> {
+        self.map.verify_invariants()
+    }
+
+    /// See [LockFreeTreeMap::height].
+    #[cfg(debug_assertions)]
+    pub fn height(&self) -> usize {
+        self.map.height()
+    }
+
+    /// See [LockFreeTreeMap::is_balanced].
+    #[cfg(debug_assertions)]
+    pub fn is_balanced(&self) -> bool {
+        self.map.is_balanced()
+    }
+
+    /// See [LockFreeTreeMap::balance_factor].
+    #[cfg(debug_assertions)]
+    pub fn balance_factor(&self, item: &T) -> Option<i64> {
+        self.map.balance_factor(item)
+    }
+}
+
+/// Iterator returned by [LockFreeBinaryTree::range].
+pub struct RangeIter<'a, T> {
+    inner: crate::treemap::RangeIter<'a, T, ()>,
 }
 
+impl<'a, T> Iterator for RangeIter<'a, T> {
+    type Item = DerefGuard<'a, T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|pair| DerefGuard::new(&pair.0))
+    }
+}
+
+#[cfg(loom)]
 #[test]
 fn test_push() {
     loom::model(|| {
         const THREAD_N: usize = loom::MAX_THREADS - 1;
-        let queue: Arc<LockFreeBinaryTree<usize>> = Arc::new(LockFreeBinaryTree::new());
+        let queue: loom::sync::Arc<LockFreeBinaryTree<usize>> =
+            loom::sync::Arc::new(LockFreeBinaryTree::new());
         let threads: Vec<_> = (0..THREAD_N)
             .map(|n| {
                 let clone = queue.clone();
-                thread::spawn(move || {
+                loom::thread::spawn(move || {
                     assert!(clone.push(n));
                 })
             })
@@ -164,15 +178,126 @@ fn test_push() {
         for handle in threads {
             handle.join().unwrap();
         }
+        queue.verify_invariants().unwrap();
     });
 }
 
 #[test]
 fn push_test() {
+    use std::sync::atomic::Ordering;
+
     let tree = LockFreeBinaryTree::<usize>::new();
     assert!(tree.push(123));
     unsafe {
-        let head = &*tree.head.load(Ordering::Relaxed);
-        assert_eq!(123, *head.data.load(Ordering::Relaxed));
+        let head = &*tree.map.head.load(Ordering::Relaxed);
+        assert_eq!(123, (*head.data.load(Ordering::Relaxed)).0);
+    }
+    tree.verify_invariants().unwrap();
+}
+
+#[test]
+fn push_duplicate_test() {
+    let tree = LockFreeBinaryTree::<usize>::new();
+    for i in [50, 20, 70, 10, 30] {
+        assert!(tree.push(i));
     }
+    assert!(!tree.push(30));
+    assert_eq!(30, *tree.find(&30));
+    tree.verify_invariants().unwrap();
+}
+
+#[test]
+fn get_test() {
+    let tree = LockFreeBinaryTree::<usize>::new();
+    assert!(tree.get(&10).is_none());
+    assert!(!tree.contains(&10));
+
+    for i in [50, 20, 70] {
+        assert!(tree.push(i));
+    }
+    assert_eq!(Some(20), tree.get(&20).map(|item| *item));
+    assert!(tree.get(&30).is_none());
+    assert!(tree.contains(&70));
+    assert!(!tree.contains(&60));
+}
+
+#[test]
+fn range_test() {
+    let tree = LockFreeBinaryTree::<usize>::new();
+    for i in [50, 20, 70, 10, 30, 60, 80, 25] {
+        tree.push(i);
+    }
+
+    let all: Vec<usize> = tree.range(..).map(|g| *g).collect();
+    assert_eq!(vec![10, 20, 25, 30, 50, 60, 70, 80], all);
+
+    let some: Vec<usize> = tree.range(25..70).map(|g| *g).collect();
+    assert_eq!(vec![25, 30, 50, 60], some);
+
+    assert_eq!(0, tree.range(81..).count());
+    tree.verify_invariants().unwrap();
+}
+
+#[test]
+fn push_allowing_duplicates_test() {
+    /// item that is only ordered by its key
+    #[derive(Debug)]
+    struct Item {
+        key: usize,
+        order: usize,
+    }
+
+    impl PartialEq for Item {
+        fn eq(&self, other: &Self) -> bool {
+            self.key == other.key
+        }
+    }
+    impl Eq for Item {}
+    impl PartialOrd for Item {
+        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+    impl Ord for Item {
+        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+            self.key.cmp(&other.key)
+        }
+    }
+
+    let tree = LockFreeBinaryTree::new();
+    for (order, key) in [5, 3, 5, 8, 5, 3, 4].into_iter().enumerate() {
+        tree.push_allowing_duplicates(Item { key, order });
+    }
+    assert!(!tree.push(Item { key: 5, order: 7 }));
+
+    let fives: Vec<usize> = tree
+        .find_all(&Item { key: 5, order: 0 })
+        .map(|item| item.order)
+        .collect();
+    assert_eq!(vec![0, 2, 4], fives);
+    assert_eq!(2, tree.find_all(&Item { key: 3, order: 0 }).count());
+    assert_eq!(0, tree.find_all(&Item { key: 6, order: 0 }).count());
+    assert_eq!(7, tree.range(..).count());
+    tree.verify_invariants().unwrap();
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn height_test() {
+    let tree = LockFreeBinaryTree::<usize>::new();
+    assert_eq!(0, tree.height());
+    assert!(tree.is_balanced());
+
+    for i in [20, 10, 30, 40] {
+        tree.push(i);
+    }
+    assert_eq!(3, tree.height());
+    assert!(tree.is_balanced());
+    assert_eq!(Some(-1), tree.balance_factor(&20));
+    assert_eq!(None, tree.balance_factor(&25));
+
+    tree.push(50);
+    assert!(!tree.is_balanced());
+    assert_eq!(Some(-2), tree.balance_factor(&30));
+    tree.verify_invariants().unwrap();
 }
@@ -0,0 +1,127 @@
+use crate::treemap::{LockFreeTreeMap, RangeIter};
+
+/// Key that exposes its byte representation. The `Ord` implementation of the
+/// key must order keys the same way as comparing their bytes
+/// lexicographically, wrap the key in [LexicographicBytes] when it does not.
+pub trait Bytes {
+    fn as_bytes(&self) -> &[u8];
+}
+
+impl Bytes for String {
+    fn as_bytes(&self) -> &[u8] {
+        str::as_bytes(self)
+    }
+}
+
+impl Bytes for &str {
+    fn as_bytes(&self) -> &[u8] {
+        str::as_bytes(self)
+    }
+}
+
+impl Bytes for Vec<u8> {
+    fn as_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Bytes for &[u8] {
+    fn as_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Wrapper that orders any byte-like value by comparing its bytes
+/// lexicographically.
+#[derive(Debug, Clone)]
+pub struct LexicographicBytes<B: AsRef<[u8]>>(pub B);
+
+impl<B: AsRef<[u8]>> Bytes for LexicographicBytes<B> {
+    fn as_bytes(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+impl<B: AsRef<[u8]>> PartialEq for LexicographicBytes<B> {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl<B: AsRef<[u8]>> Eq for LexicographicBytes<B> {}
+
+impl<B: AsRef<[u8]>> PartialOrd for LexicographicBytes<B> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<B: AsRef<[u8]>> Ord for LexicographicBytes<B> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+/// Iterator returned by [LockFreeTreeMap::scan_prefix].
+pub type PrefixIter<'a, K, V> = RangeIter<'a, K, V>;
+
+impl<K, V> LockFreeTreeMap<K, V>
+where
+    K: Bytes + Ord + Send,
+    V: Send,
+{
+    /// Iterate in ascending order over all pairs whose key starts with the
+    /// given bytes. Keys that start with the same prefix are adjacent in byte
+    /// order, so the walk stops at the first key past the prefix.
+    ///
+    /// # Arguments
+    ///
+    /// * 'prefix'  the bytes the keys should start with
+    ///
+    /// # Returns
+    ///
+    /// A [PrefixIter] yielding a guard for every matching pair.
+    pub fn scan_prefix<'a>(&'a self, prefix: &'a [u8]) -> PrefixIter<'a, K, V> {
+        self.range_by(move |key| {
+            let bytes = key.as_bytes();
+            if bytes.starts_with(prefix) {
+                std::cmp::Ordering::Equal
+            } else {
+                bytes.cmp(prefix)
+            }
+        })
+    }
+}
+
+#[test]
+fn scan_prefix_test() {
+    let map = LockFreeTreeMap::new();
+    for (i, path) in ["/usr/bin", "/etc", "/usr", "/usr/lib", "/var", "/usrx"]
+        .into_iter()
+        .enumerate()
+    {
+        map.insert(path.to_string(), i);
+    }
+
+    let found: Vec<String> = map
+        .scan_prefix(b"/usr/")
+        .map(|pair| pair.0.clone())
+        .collect();
+    assert_eq!(vec!["/usr/bin", "/usr/lib"], found);
+    assert_eq!(4, map.scan_prefix(b"/usr").count());
+    assert_eq!(6, map.scan_prefix(b"").count());
+    assert_eq!(0, map.scan_prefix(b"/tmp").count());
+    map.verify_invariants().unwrap();
+}
+
+#[test]
+fn lexicographic_bytes_test() {
+    let map = LockFreeTreeMap::new();
+    for key in [&[2u8, 1][..], &[1, 2, 3], &[1, 2], &[1, 3]] {
+        map.insert(LexicographicBytes(key), ());
+    }
+
+    let found: Vec<&[u8]> = map.scan_prefix(&[1, 2]).map(|pair| pair.0 .0).collect();
+    assert_eq!(vec![&[1u8, 2][..], &[1, 2, 3]], found);
+    map.verify_invariants().unwrap();
+}
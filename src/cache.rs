@@ -0,0 +1,83 @@
+use crate::treemap::{DerefGuard, LockFreeTreeMap};
+
+/// Memoizes the values computed for keys, for any number of threads at once.
+/// The value of a key is computed once, threads that ask for a key that is
+/// being computed wait for the result instead of computing it again.
+///
+/// The cache is built on [LockFreeTreeMap::get_or_insert_with], so keys are
+/// ordered instead of hashed. While a value is computed its key sits in the
+/// tree as a pending node, which is the "computing" state next to absent and
+/// present. Computed values are never evicted, there is no TTL cache in this
+/// crate to compose with.
+pub struct LockFreeCache<K, V> {
+    map: LockFreeTreeMap<K, V>,
+}
+
+impl<K: Send + Ord + Clone, V: Send> Default for LockFreeCache<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Send + Ord + Clone, V: Send> LockFreeCache<K, V> {
+    pub fn new() -> Self {
+        Self {
+            map: LockFreeTreeMap::new(),
+        }
+    }
+
+    /// Get the value of the given key, computing it with `f` when it is not
+    /// cached yet. `f` is called at most once per key, no matter how many
+    /// threads ask for the key at the same time.
+    ///
+    /// # Panics
+    ///
+    /// The panic of `f` is propagated. The key stays uncached, the next
+    /// thread waiting for it computes it with its own `f`.
+    pub fn get_or_compute<F: FnOnce(&K) -> V>(&self, key: K, f: F) -> DerefGuard<'_, V> {
+        self.map.get_or_insert_with_key(key, f)
+    }
+
+    /// Get the cached value of the given key without computing it. A value
+    /// that is still being computed is not returned.
+    pub fn get(&self, key: &K) -> Option<DerefGuard<'_, V>> {
+        let pair = self.map.find(key)?;
+        Some(DerefGuard::new(&pair.1))
+    }
+}
+
+#[test]
+fn get_or_compute_test() {
+    let cache = LockFreeCache::new();
+    assert!(cache.get(&3).is_none());
+    assert_eq!(9, *cache.get_or_compute(3, |key| key * key));
+    assert_eq!(9, *cache.get_or_compute(3, |_| unreachable!()));
+    assert_eq!(Some(9), cache.get(&3).map(|value| *value));
+}
+
+#[test]
+fn get_or_compute_once_test() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+
+    for round in 0..50usize {
+        let cache = LockFreeCache::new();
+        let calls = AtomicUsize::new(0);
+        let barrier = Barrier::new(16);
+
+        std::thread::scope(|scope| {
+            for _ in 0..16 {
+                scope.spawn(|| {
+                    barrier.wait();
+                    let value = cache.get_or_compute(round, |key| {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        std::thread::yield_now();
+                        key + 1
+                    });
+                    assert_eq!(round + 1, *value);
+                });
+            }
+        });
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,56 @@
+use crate::binarytree::{LockFreeBinaryTree, RangeIter};
+
+/// Key made out of two fields that is ordered lexicographically, first by the
+/// `A` field and by the `B` field when the `A` fields are equal. Storing these
+/// keys in a [LockFreeBinaryTree] makes it possible to look up all entries
+/// that share the same `A` field, which allows the tree to be used as a
+/// concurrent secondary index. Exact `(A, B)` lookups go through
+/// [LockFreeBinaryTree::get] and [LockFreeBinaryTree::contains].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LockFreeCompositeKey<A: Ord, B: Ord>(pub A, pub B);
+
+impl<A: Ord, B: Ord> LockFreeCompositeKey<A, B> {
+    pub fn new(a: A, b: B) -> Self {
+        Self(a, b)
+    }
+}
+
+impl<A, B> LockFreeBinaryTree<LockFreeCompositeKey<A, B>>
+where
+    A: Ord + Send,
+    B: Ord + Send,
+{
+    /// Iterate over all entries whose first field equals the given prefix.
+    ///
+    /// # Arguments
+    ///
+    /// * 'a'   the first field of the keys to yield
+    ///
+    /// # Returns
+    ///
+    /// A [RangeIter] yielding the matching keys ordered by their second field.
+    pub fn prefix_range<'a>(&'a self, a: &'a A) -> RangeIter<'a, LockFreeCompositeKey<A, B>> {
+        self.range_by(move |key| key.0.cmp(a))
+    }
+}
+
+#[test]
+fn prefix_range_test() {
+    let tree = LockFreeBinaryTree::new();
+    for (a, b) in [(2, 'b'), (1, 'z'), (2, 'a'), (3, 'a'), (2, 'c'), (1, 'a')] {
+        assert!(tree.push(LockFreeCompositeKey::new(a, b)));
+    }
+
+    let found: Vec<char> = tree.prefix_range(&2).map(|key| key.1).collect();
+    assert_eq!(vec!['a', 'b', 'c'], found);
+    assert_eq!(0, tree.prefix_range(&4).count());
+
+    let exact = LockFreeCompositeKey::new(1, 'z');
+    assert_eq!(Some(exact.clone()), tree.get(&exact).map(|key| key.clone()));
+    assert!(tree.contains(&LockFreeCompositeKey::new(3, 'a')));
+
+    let missing = LockFreeCompositeKey::new(2, 'd');
+    assert!(tree.get(&missing).is_none());
+    assert!(!tree.contains(&LockFreeCompositeKey::new(4, 'a')));
+    tree.verify_invariants().unwrap();
+}
@@ -0,0 +1,203 @@
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+const SEGMENTS: usize = usize::BITS as usize;
+
+struct Slot<T> {
+    ready: AtomicBool,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// Array that can be appended to from multiple threads without ever moving
+/// its elements. The elements live in segments of exponentially growing size:
+/// segment 0 holds 1 element, segment 1 holds 2, segment 2 holds 4 and so on.
+/// A segment is allocated on first use and never reallocated, therefore a
+/// reference to an element stays valid for as long as the array exists.
+pub struct LockFreeGrowableArray<T> {
+    len: AtomicUsize,
+    segments: [AtomicPtr<Slot<T>>; SEGMENTS],
+}
+
+unsafe impl<T: Send> Send for LockFreeGrowableArray<T> {}
+unsafe impl<T: Send + Sync> Sync for LockFreeGrowableArray<T> {}
+
+impl<T> Default for LockFreeGrowableArray<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> LockFreeGrowableArray<T> {
+    pub fn new() -> Self {
+        Self {
+            len: AtomicUsize::new(0),
+            segments: std::array::from_fn(|_| AtomicPtr::new(std::ptr::null_mut())),
+        }
+    }
+
+    /// Append an item to the array. Claiming the index is a single
+    /// `fetch_add`, the only CAS involved is the one that publishes a newly
+    /// allocated segment.
+    ///
+    /// # Returns
+    ///
+    /// The index the item is stored at.
+    pub fn push(&self, item: T) -> usize {
+        let index = self.len.fetch_add(1, Ordering::Relaxed);
+        let (segment, offset) = Self::location(index);
+
+        let slots = self.segment_or_allocate(segment);
+        unsafe {
+            let slot = &*slots.add(offset);
+            (*slot.value.get()).write(item);
+            slot.ready.store(true, Ordering::Release);
+        }
+        index
+    }
+
+    /// Get the item at the given index.
+    ///
+    /// # Returns
+    ///
+    /// None when no item was pushed at this index, or when the push that
+    /// claimed it did not finish writing the item yet.
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index >= self.len.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        let (segment, offset) = Self::location(index);
+        let slots = self.segments[segment].load(Ordering::Acquire);
+        if slots.is_null() {
+            return None;
+        }
+
+        unsafe {
+            let slot = &*slots.add(offset);
+            if !slot.ready.load(Ordering::Acquire) {
+                return None;
+            }
+            Some((*slot.value.get()).assume_init_ref())
+        }
+    }
+
+    /// Number of claimed indices. Includes pushes that are still in progress,
+    /// so [LockFreeGrowableArray::get] can return None for indices below it.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterate over all items in index order, skipping pushes that did not
+    /// finish yet.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        (0..self.len()).filter_map(move |index| self.get(index))
+    }
+
+    /// translate an index to the segment it resides in and the offset inside
+    /// that segment.
+    fn location(index: usize) -> (usize, usize) {
+        let position = index + 1;
+        let segment = (usize::BITS - 1 - position.leading_zeros()) as usize;
+        (segment, position - (1 << segment))
+    }
+
+    fn segment_or_allocate(&self, segment: usize) -> *mut Slot<T> {
+        let current = self.segments[segment].load(Ordering::Acquire);
+        if !current.is_null() {
+            return current;
+        }
+
+        let slots: Box<[Slot<T>]> = (0..1usize << segment)
+            .map(|_| Slot {
+                ready: AtomicBool::new(false),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+            })
+            .collect();
+        let new = Box::into_raw(slots) as *mut Slot<T>;
+
+        match self.segments[segment].compare_exchange(
+            std::ptr::null_mut(),
+            new,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => new,
+            // other thread was quicker, use its segment instead
+            Err(winner) => {
+                unsafe { drop(Self::segment_box(new, segment)) };
+                winner
+            }
+        }
+    }
+
+    unsafe fn segment_box(slots: *mut Slot<T>, segment: usize) -> Box<[Slot<T>]> {
+        Box::from_raw(std::ptr::slice_from_raw_parts_mut(slots, 1 << segment))
+    }
+}
+
+impl<T> Drop for LockFreeGrowableArray<T> {
+    fn drop(&mut self) {
+        for (segment, slots) in self.segments.iter_mut().enumerate() {
+            let slots = *slots.get_mut();
+            if slots.is_null() {
+                continue;
+            }
+
+            let mut slots = unsafe { Self::segment_box(slots, segment) };
+            for slot in slots.iter_mut() {
+                if *slot.ready.get_mut() {
+                    unsafe { slot.value.get_mut().assume_init_drop() };
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn push_get_test() {
+    let array = LockFreeGrowableArray::new();
+    for i in 0..100usize {
+        assert_eq!(i, array.push(i.to_string()));
+    }
+
+    let first: *const String = array.get(0).unwrap();
+    array.push("more".to_string());
+
+    assert_eq!(101, array.len());
+    assert_eq!(Some("99"), array.get(99).map(String::as_str));
+    assert_eq!(Some("more"), array.get(100).map(String::as_str));
+    assert_eq!(None, array.get(101));
+    // elements never move
+    assert_eq!(first, array.get(0).unwrap() as *const String);
+}
+
+#[cfg(loom)]
+#[test]
+fn test_push() {
+    loom::model(|| {
+        const THREAD_N: usize = loom::MAX_THREADS - 1;
+        let array = loom::sync::Arc::new(LockFreeGrowableArray::new());
+        let threads: Vec<_> = (0..THREAD_N)
+            .map(|n| {
+                let clone = array.clone();
+                loom::thread::spawn(move || {
+                    let index = clone.push(n);
+                    assert_eq!(Some(&n), clone.get(index));
+                })
+            })
+            .collect();
+
+        for handle in threads {
+            handle.join().unwrap();
+        }
+
+        let mut all: Vec<usize> = array.iter().copied().collect();
+        all.sort();
+        assert_eq!((0..THREAD_N).collect::<Vec<_>>(), all);
+    });
+}
@@ -0,0 +1,186 @@
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+pub use lockfree_derive::IntrusiveLink;
+
+/// Type that embeds the link to the next element of a
+/// [LockFreeIntrusiveStack]. Can be derived for structs with a
+/// `next: AtomicPtr<Self>` field.
+pub trait IntrusiveLink: Sized {
+    fn link(&self) -> &AtomicPtr<Self>;
+}
+
+/// Treiber stack that stores its elements in place. The elements carry the
+/// link to the next element themselves, so pushing does not allocate. The
+/// stack never owns its elements, the caller keeps them alive and is in charge
+/// of freeing them.
+pub struct LockFreeIntrusiveStack<T: IntrusiveLink> {
+    head: AtomicPtr<T>,
+}
+
+impl<T: IntrusiveLink> Default for LockFreeIntrusiveStack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: IntrusiveLink> LockFreeIntrusiveStack<T> {
+    pub fn new() -> Self {
+        Self {
+            head: AtomicPtr::new(std::ptr::null_mut()),
+        }
+    }
+
+    /// Push an element on top of the stack.
+    ///
+    /// # Safety
+    ///
+    /// `element` must point to a valid element that stays alive until it is
+    /// taken off the stack again, and it must not be on any stack already.
+    pub unsafe fn push(&self, element: *mut T) {
+        let link = (*element).link();
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            link.store(head, Ordering::Relaxed);
+            match self.head.compare_exchange_weak(
+                head,
+                element,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                //other thread was quicker, link to its element instead
+                Err(actual) => head = actual,
+            }
+        }
+    }
+
+    /// Take the top element off the stack.
+    ///
+    /// # Safety
+    ///
+    /// While this function runs, no element may be freed or pushed again
+    /// after it was taken off the stack. The easiest way to uphold this is to
+    /// only pop from a single thread, pushing from any amount of threads is
+    /// allowed. Otherwise a popped element could be read after it is freed, or
+    /// the top of the stack could change and change back in between the load
+    /// and the CAS (the ABA problem).
+    pub unsafe fn pop(&self) -> Option<*mut T> {
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            if head.is_null() {
+                return None;
+            }
+
+            let next = (*head).link().load(Ordering::Relaxed);
+            match self
+                .head
+                .compare_exchange_weak(head, next, Ordering::Acquire, Ordering::Acquire)
+            {
+                Ok(_) => return Some(head),
+                Err(actual) => head = actual,
+            }
+        }
+    }
+
+    /// Take all elements off the stack with a single swap. This is safe to
+    /// call from any amount of threads since no element is read before it is
+    /// detached.
+    ///
+    /// # Returns
+    ///
+    /// Iterator over the detached elements, from top to bottom.
+    pub fn take_all(&self) -> TakeAll<T> {
+        TakeAll {
+            current: self.head.swap(std::ptr::null_mut(), Ordering::Acquire),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Relaxed).is_null()
+    }
+}
+
+/// Iterator returned by [LockFreeIntrusiveStack::take_all].
+pub struct TakeAll<T: IntrusiveLink> {
+    current: *mut T,
+}
+
+impl<T: IntrusiveLink> Iterator for TakeAll<T> {
+    type Item = *mut T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.current.is_null() {
+            return None;
+        }
+
+        let element = self.current;
+        // the detached chain is only reachable through this iterator
+        self.current = unsafe { (*element).link().load(Ordering::Relaxed) };
+        Some(element)
+    }
+}
+
+#[cfg(test)]
+#[derive(IntrusiveLink)]
+struct Task {
+    id: usize,
+    next: AtomicPtr<Task>,
+}
+
+#[cfg(test)]
+impl Task {
+    fn leaky(id: usize) -> *mut Self {
+        Box::into_raw(Box::new(Self {
+            id,
+            next: AtomicPtr::new(std::ptr::null_mut()),
+        }))
+    }
+}
+
+#[test]
+fn push_pop_test() {
+    let stack = LockFreeIntrusiveStack::new();
+    unsafe {
+        for id in 0..3 {
+            stack.push(Task::leaky(id));
+        }
+
+        let top = stack.pop().unwrap();
+        assert_eq!(2, (*top).id);
+        drop(Box::from_raw(top));
+
+        let rest: Vec<usize> = stack
+            .take_all()
+            .map(|task| Box::from_raw(task).id)
+            .collect();
+        assert_eq!(vec![1, 0], rest);
+        assert!(stack.is_empty());
+        assert!(stack.pop().is_none());
+    }
+}
+
+#[cfg(loom)]
+#[test]
+fn test_push() {
+    loom::model(|| {
+        const THREAD_N: usize = loom::MAX_THREADS - 1;
+        let stack = loom::sync::Arc::new(LockFreeIntrusiveStack::new());
+        let threads: Vec<_> = (0..THREAD_N)
+            .map(|n| {
+                let clone = stack.clone();
+                loom::thread::spawn(move || unsafe { clone.push(Task::leaky(n)) })
+            })
+            .collect();
+
+        for handle in threads {
+            handle.join().unwrap();
+        }
+
+        let mut ids: Vec<usize> = stack
+            .take_all()
+            .map(|task| unsafe { Box::from_raw(task).id })
+            .collect();
+        ids.sort();
+        assert_eq!((0..THREAD_N).collect::<Vec<_>>(), ids);
+    });
+}
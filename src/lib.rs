@@ -2,4 +2,20 @@
 //! containers might underperform in highly concurrent environments. Combined
 //! with the fact that this crate is made for educational purposes, makes this
 //! crate not a good candidate for usage in production.
+//!
+//! Trees that are built once and afterwards only queried should be
+//! constructed with [treebuilder::LockFreeTreeBuilder], which produces a
+//! balanced tree without paying for a CAS on every insertion.
+extern crate self as lockfree;
+
 pub mod binarytree;
+pub mod bytes;
+pub mod cache;
+pub mod compositekey;
+pub mod growablearray;
+pub mod intrusive;
+pub mod refcell;
+pub mod rope;
+pub mod table;
+pub mod treebuilder;
+pub mod treemap;
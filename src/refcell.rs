@@ -0,0 +1,175 @@
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+const WRITING: i32 = -1;
+
+/// Error returned when a [LockFreeRefCell] cannot be borrowed because of a
+/// conflicting borrow held by another thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BorrowError;
+
+impl std::fmt::Display for BorrowError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "value is already borrowed")
+    }
+}
+
+impl std::error::Error for BorrowError {}
+
+/// Multi-threaded counterpart of [std::cell::RefCell]. The borrow state is
+/// kept in a single atomic counter: a positive value is the number of
+/// readers, -1 means a writer holds the cell. Borrowing never blocks, a
+/// conflicting borrow is reported as a [BorrowError] instead. For low
+/// contention scenarios this is lighter than a `RwLock`.
+///
+/// Note that the cell is not truly lock free, a borrow holder keeps other
+/// threads from acquiring a conflicting borrow.
+pub struct LockFreeRefCell<T> {
+    borrows: AtomicI32,
+    value: UnsafeCell<T>,
+}
+
+// readers on multiple threads share a `&T`, hence the `Sync` bound on `T`
+// next to `Send` which is required for handing out `&mut T`.
+unsafe impl<T: Send> Send for LockFreeRefCell<T> {}
+unsafe impl<T: Send + Sync> Sync for LockFreeRefCell<T> {}
+
+impl<T> LockFreeRefCell<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            borrows: AtomicI32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Immutably borrow the contained value. Multiple immutable borrows can
+    /// be held at the same time.
+    ///
+    /// # Returns
+    ///
+    /// A guard that releases the borrow when dropped, or a [BorrowError]
+    /// when the value is currently mutably borrowed.
+    pub fn borrow(&self) -> Result<RefGuard<'_, T>, BorrowError> {
+        let mut current = self.borrows.load(Ordering::Relaxed);
+        loop {
+            if current == WRITING || current == i32::MAX {
+                return Err(BorrowError);
+            }
+
+            match self.borrows.compare_exchange_weak(
+                current,
+                current + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Ok(RefGuard { cell: self }),
+                // other reader was quicker, try again with its count
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Mutably borrow the contained value.
+    ///
+    /// # Returns
+    ///
+    /// A guard that releases the borrow when dropped, or a [BorrowError]
+    /// when the value is currently borrowed by anyone else.
+    pub fn borrow_mut(&self) -> Result<MutRefGuard<'_, T>, BorrowError> {
+        self.borrows
+            .compare_exchange(0, WRITING, Ordering::Acquire, Ordering::Relaxed)
+            .map(|_| MutRefGuard { cell: self })
+            .map_err(|_| BorrowError)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+/// Immutable borrow of a [LockFreeRefCell].
+pub struct RefGuard<'a, T> {
+    cell: &'a LockFreeRefCell<T>,
+}
+
+impl<'a, T> Deref for RefGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.cell.value.get() }
+    }
+}
+
+impl<'a, T> Drop for RefGuard<'a, T> {
+    fn drop(&mut self) {
+        self.cell.borrows.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Mutable borrow of a [LockFreeRefCell].
+pub struct MutRefGuard<'a, T> {
+    cell: &'a LockFreeRefCell<T>,
+}
+
+impl<'a, T> Deref for MutRefGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.cell.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutRefGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.cell.value.get() }
+    }
+}
+
+impl<'a, T> Drop for MutRefGuard<'a, T> {
+    fn drop(&mut self) {
+        self.cell.borrows.store(0, Ordering::Release);
+    }
+}
+
+#[test]
+fn borrow_test() {
+    let cell = LockFreeRefCell::new(5);
+    {
+        let first = cell.borrow().unwrap();
+        let second = cell.borrow().unwrap();
+        assert_eq!(10, *first + *second);
+        assert_eq!(BorrowError, cell.borrow_mut().err().unwrap());
+    }
+
+    {
+        let mut writer = cell.borrow_mut().unwrap();
+        *writer += 1;
+        assert!(cell.borrow().is_err());
+    }
+
+    assert_eq!(6, *cell.borrow().unwrap());
+}
+
+#[cfg(loom)]
+#[test]
+fn test_borrow_mut() {
+    loom::model(|| {
+        let cell = loom::sync::Arc::new(LockFreeRefCell::new(0usize));
+        let threads: Vec<_> = (0..loom::MAX_THREADS - 1)
+            .map(|_| {
+                let clone = cell.clone();
+                loom::thread::spawn(move || {
+                    while clone.borrow_mut().map(|mut value| *value += 1).is_err() {
+                        loom::thread::yield_now();
+                    }
+                })
+            })
+            .collect();
+
+        for handle in threads {
+            handle.join().unwrap();
+        }
+        assert_eq!(loom::MAX_THREADS - 1, *cell.borrow().unwrap());
+    });
+}
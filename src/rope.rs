@@ -0,0 +1,491 @@
+use std::ops::{Deref, Range};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Arc;
+
+/// Part of the [LockFreeRopeString]. The text of a subtree is the text of the
+/// left subtree, followed by the piece of this node, followed by the text of
+/// the right subtree. A node never changes once it is part of the rope.
+struct Node {
+    piece: Piece,
+    /// byte length of the whole subtree rooted at this node
+    len: usize,
+    /// amount of nodes on the longest path from this node down to a leaf
+    height: usize,
+    left: *const Node,
+    right: *const Node,
+}
+
+/// Slice of a text that is shared between all pieces cut out of it, so
+/// splitting a piece never copies the text.
+#[derive(Clone)]
+struct Piece {
+    text: Arc<str>,
+    range: Range<usize>,
+}
+
+impl Piece {
+    fn new(text: &str) -> Self {
+        Self {
+            text: text.into(),
+            range: 0..text.len(),
+        }
+    }
+
+    /// split the piece in the part before and the part from the given byte
+    /// offset on.
+    fn split(&self, position: usize) -> (Piece, Piece) {
+        let middle = self.range.start + position;
+        let before = Piece {
+            text: self.text.clone(),
+            range: self.range.start..middle,
+        };
+        let after = Piece {
+            text: self.text.clone(),
+            range: middle..self.range.end,
+        };
+        (before, after)
+    }
+}
+
+impl Deref for Piece {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.text[self.range.clone()]
+    }
+}
+
+/// Node that got replaced by a copy. Concurrent readers might still be
+/// walking over it, so it is kept alive until the rope is dropped.
+struct Retired {
+    node: *mut Node,
+    next: *mut Retired,
+}
+
+/// String that is stored as a balanced binary tree of string slices. The
+/// position of a node in the in-order traversal is its position in the
+/// string.
+///
+/// Unlike the nodes of [crate::binarytree::LockFreeBinaryTree], the nodes of
+/// a rope are immutable. An operation builds copies of the nodes on the path
+/// it changes, rebalancing them the way an AVL tree does, and publishes the
+/// new root with a single CAS. When another thread published a root first,
+/// the operation starts over from that root. Every operation therefore
+/// applies to a state the rope really was in, readers always walk a
+/// consistent version of it, and the depth stays logarithmic. The replaced
+/// nodes are freed when the rope is dropped.
+///
+/// Positions are byte offsets.
+pub struct LockFreeRopeString {
+    root: AtomicPtr<Node>,
+    retired: AtomicPtr<Retired>,
+}
+
+impl Default for LockFreeRopeString {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<&str> for LockFreeRopeString {
+    fn from(text: &str) -> Self {
+        let rope = Self::new();
+        rope.insert(0, text);
+        rope
+    }
+}
+
+impl LockFreeRopeString {
+    pub fn new() -> Self {
+        Self {
+            root: AtomicPtr::new(std::ptr::null_mut()),
+            retired: AtomicPtr::new(std::ptr::null_mut()),
+        }
+    }
+
+    /// Length of the string in bytes.
+    pub fn len(&self) -> usize {
+        subtree_len(self.root.load(Ordering::Acquire))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Insert a string at the given byte position. Positions past the end of
+    /// the rope append to it. When the position falls inside a piece, that
+    /// piece is split in two without copying its text and the string goes
+    /// in between, so the cost is logarithmic in the amount of pieces.
+    ///
+    /// # Panics
+    ///
+    /// When the position is not on a char boundary.
+    pub fn insert(&self, position: usize, s: &str) {
+        if s.is_empty() {
+            return;
+        }
+        self.update(|attempt, root| attempt.insert(root, position, s));
+    }
+
+    /// Append the given rope to the end of this rope. The trees are joined
+    /// along the right spine of this rope, so the cost is logarithmic in the
+    /// size of both ropes.
+    pub fn concat(&self, mut other: LockFreeRopeString) {
+        // nobody else can read the other rope, it is owned by this call
+        let other_root = *other.root.get_mut();
+        if other_root.is_null() {
+            return;
+        }
+
+        self.update(|attempt, root| attempt.join(root, other_root));
+        *other.root.get_mut() = std::ptr::null_mut();
+    }
+
+    /// Build a new version of the rope out of the current root and publish
+    /// it. A lost CAS means another thread published a version first, the
+    /// change is applied to that version instead.
+    fn update<F>(&self, change: F)
+    where
+        F: Fn(&mut Attempt, *const Node) -> *const Node,
+    {
+        loop {
+            let root = self.root.load(Ordering::Acquire);
+            let mut attempt = Attempt::default();
+            let new_root = change(&mut attempt, root) as *mut Node;
+            if self
+                .root
+                .compare_exchange(root, new_root, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                attempt.fresh.clear();
+                for node in attempt.replaced.drain(..) {
+                    self.retire(node as *mut Node);
+                }
+                return;
+            }
+        }
+    }
+
+    fn retire(&self, node: *mut Node) {
+        let retired = Box::into_raw(Box::new(Retired {
+            node,
+            next: self.retired.load(Ordering::Relaxed),
+        }));
+        loop {
+            let next = unsafe { (*retired).next };
+            match self.retired.compare_exchange_weak(
+                next,
+                retired,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(head) => unsafe { (*retired).next = head },
+            }
+        }
+    }
+}
+
+fn subtree_len(node: *const Node) -> usize {
+    unsafe { node.as_ref() }.map_or(0, |node| node.len)
+}
+
+fn height(node: *const Node) -> usize {
+    unsafe { node.as_ref() }.map_or(0, |node| node.height)
+}
+
+/// Nodes touched while building a single new version of the rope.
+#[derive(Default)]
+struct Attempt {
+    /// nodes allocated by this attempt, they are freed when the attempt is
+    /// not published
+    fresh: Vec<*mut Node>,
+    /// published nodes that got copied by this attempt
+    replaced: Vec<*const Node>,
+}
+
+impl Attempt {
+    fn node(&mut self, piece: Piece, left: *const Node, right: *const Node) -> *const Node {
+        let node = Box::into_raw(Box::new(Node {
+            len: subtree_len(left) + piece.len() + subtree_len(right),
+            height: 1 + height(left).max(height(right)),
+            piece,
+            left,
+            right,
+        }));
+        self.fresh.push(node);
+        node
+    }
+
+    /// take the given node apart to build new nodes out of its parts.
+    fn take(&mut self, node: *const Node) -> (Piece, *const Node, *const Node) {
+        let parts = unsafe { ((*node).piece.clone(), (*node).left, (*node).right) };
+        match self
+            .fresh
+            .iter()
+            .position(|fresh| std::ptr::eq(*fresh, node))
+        {
+            // never published, nobody else can see it
+            Some(index) => drop(unsafe { Box::from_raw(self.fresh.swap_remove(index)) }),
+            None => self.replaced.push(node),
+        }
+        parts
+    }
+
+    fn insert(&mut self, node: *const Node, position: usize, s: &str) -> *const Node {
+        if node.is_null() {
+            return self.node(Piece::new(s), std::ptr::null(), std::ptr::null());
+        }
+
+        let (piece, left, right) = self.take(node);
+        let left_len = subtree_len(left);
+        if position <= left_len {
+            let left = self.insert(left, position, s);
+            return self.balance(piece, left, right);
+        }
+
+        let position = position - left_len;
+        if position < piece.len() {
+            assert!(piece.is_char_boundary(position));
+            let (before, after) = piece.split(position);
+            let left = self.join_with(left, before, std::ptr::null());
+            let right = self.join_with(std::ptr::null(), after, right);
+            return self.join_with(left, Piece::new(s), right);
+        }
+
+        let right = self.insert(right, position - piece.len(), s);
+        self.balance(piece, left, right)
+    }
+
+    /// join two trees into a tree holding the text of `left` followed by the
+    /// text of `right`.
+    fn join(&mut self, left: *const Node, right: *const Node) -> *const Node {
+        if left.is_null() {
+            return right;
+        }
+        if right.is_null() {
+            return left;
+        }
+
+        let (piece, right) = self.remove_first(right);
+        self.join_with(left, piece, right)
+    }
+
+    /// join two trees with the given piece in between. The piece is placed on
+    /// the spine of the higher tree where both heights match.
+    fn join_with(&mut self, left: *const Node, piece: Piece, right: *const Node) -> *const Node {
+        if height(left) > height(right) + 1 {
+            let (left_piece, left_left, left_right) = self.take(left);
+            let joined = self.join_with(left_right, piece, right);
+            return self.balance(left_piece, left_left, joined);
+        }
+
+        if height(right) > height(left) + 1 {
+            let (right_piece, right_left, right_right) = self.take(right);
+            let joined = self.join_with(left, piece, right_left);
+            return self.balance(right_piece, joined, right_right);
+        }
+
+        self.node(piece, left, right)
+    }
+
+    fn remove_first(&mut self, node: *const Node) -> (Piece, *const Node) {
+        let (piece, left, right) = self.take(node);
+        if left.is_null() {
+            return (piece, right);
+        }
+
+        let (first, left) = self.remove_first(left);
+        (first, self.balance(piece, left, right))
+    }
+
+    /// build a node out of the given parts, rotating them when the heights of
+    /// the subtrees differ by more than one.
+    fn balance(&mut self, piece: Piece, left: *const Node, right: *const Node) -> *const Node {
+        if height(left) > height(right) + 1 {
+            let (left_piece, left_left, left_right) = self.take(left);
+            if height(left_left) >= height(left_right) {
+                let right = self.node(piece, left_right, right);
+                return self.node(left_piece, left_left, right);
+            }
+
+            let (middle_piece, middle_left, middle_right) = self.take(left_right);
+            let left = self.node(left_piece, left_left, middle_left);
+            let right = self.node(piece, middle_right, right);
+            return self.node(middle_piece, left, right);
+        }
+
+        if height(right) > height(left) + 1 {
+            let (right_piece, right_left, right_right) = self.take(right);
+            if height(right_right) >= height(right_left) {
+                let left = self.node(piece, left, right_left);
+                return self.node(right_piece, left, right_right);
+            }
+
+            let (middle_piece, middle_left, middle_right) = self.take(right_left);
+            let left = self.node(piece, left, middle_left);
+            let right = self.node(right_piece, middle_right, right_right);
+            return self.node(middle_piece, left, right);
+        }
+
+        self.node(piece, left, right)
+    }
+}
+
+impl Drop for Attempt {
+    fn drop(&mut self) {
+        for node in self.fresh.drain(..) {
+            drop(unsafe { Box::from_raw(node) });
+        }
+    }
+}
+
+impl std::fmt::Display for LockFreeRopeString {
+    /// in-order traversal over all pieces of the rope.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut stack: Vec<*const Node> = Vec::new();
+        let mut current: *const Node = self.root.load(Ordering::Acquire);
+        loop {
+            while let Some(node) = unsafe { current.as_ref() } {
+                stack.push(current);
+                current = node.left;
+            }
+
+            let Some(node) = stack.pop() else {
+                return Ok(());
+            };
+            let node = unsafe { &*node };
+            f.write_str(&node.piece)?;
+            current = node.right;
+        }
+    }
+}
+
+impl Drop for LockFreeRopeString {
+    fn drop(&mut self) {
+        let mut nodes: Vec<*const Node> = vec![*self.root.get_mut()];
+        while let Some(node) = nodes.pop() {
+            if node.is_null() {
+                continue;
+            }
+
+            let node = unsafe { Box::from_raw(node as *mut Node) };
+            nodes.push(node.left);
+            nodes.push(node.right);
+        }
+
+        let mut retired = *self.retired.get_mut();
+        while !retired.is_null() {
+            let boxed = unsafe { Box::from_raw(retired) };
+            unsafe { drop(Box::from_raw(boxed.node)) };
+            retired = boxed.next;
+        }
+    }
+}
+
+#[test]
+fn insert_test() {
+    let rope = LockFreeRopeString::from("held");
+    rope.insert(3, "lo wor");
+    rope.insert(9, "l");
+    rope.insert(0, ">");
+    rope.insert(100, "!");
+
+    assert_eq!(">hello world!", rope.to_string());
+    assert_eq!(13, rope.len());
+}
+
+#[test]
+fn concat_test() {
+    let rope = LockFreeRopeString::from("lock");
+    rope.concat(LockFreeRopeString::from("free"));
+    rope.concat(LockFreeRopeString::new());
+    rope.insert(4, " ");
+    rope.insert(9, " rope");
+
+    assert_eq!("lock free rope", rope.to_string());
+    assert_eq!(14, rope.len());
+}
+
+#[test]
+fn balanced_test() {
+    let rope = LockFreeRopeString::new();
+    for i in 0..1024 {
+        rope.insert(rope.len(), if i % 2 == 0 { "a" } else { "b" });
+    }
+    let other = LockFreeRopeString::from("c");
+    for _ in 0..100 {
+        other.insert(0, "c");
+    }
+    rope.concat(other);
+
+    assert_eq!(1125, rope.len());
+    assert!(rope.to_string().starts_with("abab"));
+    assert!(rope.to_string().ends_with(&"c".repeat(101)));
+    // an AVL tree with n nodes is at most 1.44 log2(n) high
+    let root = unsafe { &*rope.root.load(Ordering::Acquire) };
+    assert!(root.height <= 15, "height {}", root.height);
+}
+
+#[test]
+fn concurrent_insert_test() {
+    let rope = std::sync::Arc::new(LockFreeRopeString::from("|"));
+    let threads: Vec<_> = ["a", "b", "c", "d"]
+        .into_iter()
+        .map(|s| {
+            let rope = rope.clone();
+            std::thread::spawn(move || {
+                for _ in 0..250 {
+                    // insert in front of the marker, wherever it is
+                    let marker = rope.to_string().find('|').unwrap();
+                    rope.insert(marker, s);
+                    rope.insert(rope.len(), s);
+                }
+            })
+        })
+        .collect();
+    for handle in threads {
+        handle.join().unwrap();
+    }
+
+    let text = rope.to_string();
+    assert_eq!(2001, rope.len());
+    assert_eq!(2001, text.len());
+    for s in ["a", "b", "c", "d"] {
+        assert_eq!(500, text.matches(s).count());
+    }
+}
+
+#[test]
+fn insert_into_long_piece_test() {
+    let text = "0123456789".repeat(10_000);
+    let rope = LockFreeRopeString::from(text.as_str());
+    let mut expected = text.clone();
+    for i in 0..1000 {
+        let position = (i * 7919) % expected.len();
+        rope.insert(position, "ab");
+        expected.insert_str(position, "ab");
+    }
+    assert_eq!(expected, rope.to_string());
+
+    // walk all nodes and collect the texts their pieces point into
+    let mut nodes = 0;
+    let mut texts = std::collections::HashMap::new();
+    let mut stack: Vec<*const Node> = vec![rope.root.load(Ordering::Acquire)];
+    while let Some(node) = stack.pop() {
+        let Some(node) = (unsafe { node.as_ref() }) else {
+            continue;
+        };
+        nodes += 1;
+        texts.insert(Arc::as_ptr(&node.piece.text), node.piece.text.len());
+        stack.push(node.left);
+        stack.push(node.right);
+    }
+
+    // the long text is never copied, only the inserted strings are allocated
+    assert_eq!(text.len() + 1000 * 2, texts.values().sum::<usize>());
+    assert!(nodes <= 1 + 2 * 1000);
+    let root = unsafe { &*rope.root.load(Ordering::Acquire) };
+    // an AVL tree with n nodes is at most 1.44 log2(n) high
+    assert!(root.height <= 16, "height {}", root.height);
+}
@@ -0,0 +1,658 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// generation field that did not get a generation assigned yet.
+const PENDING: u64 = 0;
+/// removal generation of an entry that was not removed.
+const LIVE: u64 = u64::MAX;
+/// generation of a reader slot that is not in use.
+const IDLE: u64 = u64::MAX;
+
+const DEFAULT_BUCKETS: usize = 64;
+
+/// Entry of a bucket chain. A removed entry stays in the chain until no
+/// reader can see it anymore, see [LockFreeTable::sweep].
+struct Entry<K, V> {
+    key: K,
+    value: V,
+    inserted: AtomicU64,
+    removed: AtomicU64,
+    next: AtomicPtr<Entry<K, V>>,
+}
+
+struct Bucket<K, V> {
+    head: AtomicPtr<Entry<K, V>>,
+    /// set while a thread unlinks removed entries from the chain
+    sweeping: AtomicBool,
+}
+
+/// Generation a reader started at. Slots are reused by later readers and
+/// only freed when the table is dropped.
+struct ReaderSlot {
+    generation: AtomicU64,
+    next: *mut ReaderSlot,
+}
+
+// `next` is only written before the slot is published on the readers list.
+unsafe impl Send for ReaderSlot {}
+unsafe impl Sync for ReaderSlot {}
+
+/// Entry that got unlinked from its chain. Readers that started before it
+/// got unlinked might still be looking at it.
+struct Retired<K, V> {
+    entry: *mut Entry<K, V>,
+    /// generation taken right after the entry got unlinked
+    unlinked: u64,
+    next: *mut Retired<K, V>,
+}
+
+/// Hash table whose iterator sees a consistent snapshot of the table. Every
+/// insert and remove gets a generation number from a global counter and
+/// stores it in the affected entry. A snapshot reads the counter once and
+/// only yields entries that were inserted at or before, and not removed at or
+/// before, that generation.
+///
+/// Generations are assigned after an entry is linked into its bucket. Any
+/// thread that encounters an entry without a generation assigns a fresh one
+/// itself, so no operation ever waits on another thread.
+///
+/// Every reader pins the generation it started at for as long as it, or a
+/// guard it handed out, exists. A removed entry is unlinked from its chain
+/// once every pinned generation is at or past its removal, and freed once
+/// every reader that started before the unlink is done. A snapshot that is
+/// kept around therefore keeps the entries removed after it was taken.
+pub struct LockFreeTable<K, V, S = RandomState> {
+    generation: AtomicU64,
+    buckets: Box<[Bucket<K, V>]>,
+    readers: AtomicPtr<ReaderSlot>,
+    retired: AtomicPtr<Retired<K, V>>,
+    hasher: S,
+}
+
+unsafe impl<K: Send, V: Send, S: Send> Send for LockFreeTable<K, V, S> {}
+unsafe impl<K: Send + Sync, V: Send + Sync, S: Sync> Sync for LockFreeTable<K, V, S> {}
+
+impl<K: Hash + Eq, V> Default for LockFreeTable<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq, V> LockFreeTable<K, V> {
+    pub fn new() -> Self {
+        Self::with_buckets(DEFAULT_BUCKETS)
+    }
+
+    /// Create a table with a fixed amount of buckets. The table does not
+    /// grow, pick a bucket count in the order of the expected amount of keys.
+    ///
+    /// # Panics
+    ///
+    /// When `buckets` is 0.
+    pub fn with_buckets(buckets: usize) -> Self {
+        Self::with_buckets_and_hasher(buckets, RandomState::new())
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> LockFreeTable<K, V, S> {
+    /// Same as [LockFreeTable::with_buckets], with the given hasher.
+    ///
+    /// # Panics
+    ///
+    /// When `buckets` is 0.
+    pub fn with_buckets_and_hasher(buckets: usize, hasher: S) -> Self {
+        assert!(buckets > 0, "a table needs at least one bucket");
+        Self {
+            generation: AtomicU64::new(0),
+            buckets: (0..buckets)
+                .map(|_| Bucket {
+                    head: AtomicPtr::new(std::ptr::null_mut()),
+                    sweeping: AtomicBool::new(false),
+                })
+                .collect(),
+            readers: AtomicPtr::new(std::ptr::null_mut()),
+            retired: AtomicPtr::new(std::ptr::null_mut()),
+            hasher,
+        }
+    }
+
+    /// Insert the given key value pair.
+    ///
+    /// # Returns
+    ///
+    /// false when the key is already present, the given pair is dropped in
+    /// that case.
+    pub fn insert(&self, key: K, value: V) -> bool {
+        let _pin = self.pin();
+        let bucket = self.bucket(&key);
+        let new_entry = Box::into_raw(Box::new(Entry {
+            key,
+            value,
+            inserted: AtomicU64::new(PENDING),
+            removed: AtomicU64::new(LIVE),
+            next: AtomicPtr::new(std::ptr::null_mut()),
+        }));
+        let key = unsafe { &(*new_entry).key };
+
+        loop {
+            let head = bucket.head.load(Ordering::SeqCst);
+            // a chain holds at most one entry per key that is not removed.
+            if let Some(existing) = self.find_live(head, key) {
+                self.assign(&existing.inserted);
+                drop(unsafe { Box::from_raw(new_entry) });
+                return false;
+            }
+
+            unsafe { (*new_entry).next.store(head, Ordering::SeqCst) };
+            // a lost CAS means the chain changed after it was scanned, scan
+            // it again to catch a concurrent insert of the same key.
+            if bucket
+                .head
+                .compare_exchange(head, new_entry, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                self.assign(unsafe { &(*new_entry).inserted });
+                return true;
+            }
+        }
+    }
+
+    /// Remove the given key from the table. Afterwards the bucket of the key
+    /// is swept, see [LockFreeTable::sweep].
+    ///
+    /// # Returns
+    ///
+    /// false when the key is not present or another thread removed it first.
+    pub fn remove(&self, key: &K) -> bool {
+        let bucket = self.bucket(key);
+        let won = {
+            let _pin = self.pin();
+            let head = bucket.head.load(Ordering::SeqCst);
+            let Some(entry) = self.find_live(head, key) else {
+                return false;
+            };
+
+            // the removal must be ordered after the insert of the entry.
+            self.assign(&entry.inserted);
+            let won = entry
+                .removed
+                .compare_exchange(LIVE, PENDING, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok();
+            self.assign(&entry.removed);
+            won
+        };
+
+        self.sweep(bucket);
+        won
+    }
+
+    /// Get the value of the given key.
+    pub fn get(&self, key: &K) -> Option<EntryGuard<'_, K, V>> {
+        let pin = self.pin();
+        let mut current = self.bucket(key).head.load(Ordering::SeqCst);
+        while let Some(entry) = unsafe { current.as_ref() } {
+            if entry.key == *key && self.visible(entry, pin.generation) {
+                return Some(EntryGuard {
+                    _pin: Arc::new(pin),
+                    entry,
+                });
+            }
+            current = entry.next.load(Ordering::SeqCst);
+        }
+        None
+    }
+
+    /// Iterate over a snapshot of the table. The iterator yields exactly the
+    /// entries that are present at the moment the snapshot is taken, inserts
+    /// and removes that happen afterwards are invisible to it.
+    ///
+    /// The table has a fixed bucket count and never resizes, so the snapshot
+    /// does not have to account for entries moving between buckets. The
+    /// iterator and the yielded entries share the pinned generation through
+    /// an [Arc] and can be sent to other threads.
+    pub fn iter_snapshot(&self) -> SnapshotIter<'_, K, V, S> {
+        SnapshotIter {
+            table: self,
+            pin: Arc::new(self.pin()),
+            bucket: 0,
+            current: std::ptr::null(),
+        }
+    }
+
+    fn bucket(&self, key: &K) -> &Bucket<K, V> {
+        let index = self.hasher.hash_one(key) as usize % self.buckets.len();
+        &self.buckets[index]
+    }
+
+    /// walk the chain starting at `current` and return the entry with the
+    /// given key that is not removed, or is still being removed.
+    fn find_live(&self, mut current: *const Entry<K, V>, key: &K) -> Option<&Entry<K, V>> {
+        while let Some(entry) = unsafe { current.as_ref() } {
+            if entry.key == *key && self.assign(&entry.removed) == LIVE {
+                return Some(entry);
+            }
+            current = entry.next.load(Ordering::SeqCst);
+        }
+        None
+    }
+
+    /// Unlink the removed entries of the given bucket that no pinned
+    /// generation can see anymore, then free the unlinked entries no reader
+    /// can reach anymore. One thread sweeps a bucket at a time, a thread that
+    /// finds the bucket being swept leaves it to that thread.
+    ///
+    /// The sweeper does not need a pin itself: only the sweeper unlinks
+    /// entries of the bucket, and only unlinked entries are ever freed.
+    fn sweep(&self, bucket: &Bucket<K, V>) {
+        if bucket
+            .sweeping
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            let oldest = self.oldest_pin();
+            let mut link = &bucket.head;
+            let mut current = link.load(Ordering::SeqCst);
+            while let Some(entry) = unsafe { current.as_ref() } {
+                let next = entry.next.load(Ordering::SeqCst);
+                let removed = entry.removed.load(Ordering::SeqCst);
+                let dead = removed != LIVE && removed != PENDING && removed <= oldest;
+                // only the bucket head can change under the sweeper, when an
+                // insert pushed an entry in front of it. A later sweep unlinks
+                // the entry then.
+                if dead
+                    && link
+                        .compare_exchange(current, next, Ordering::SeqCst, Ordering::SeqCst)
+                        .is_ok()
+                {
+                    self.retire(current);
+                } else {
+                    link = &entry.next;
+                }
+                current = next;
+            }
+            bucket.sweeping.store(false, Ordering::SeqCst);
+        }
+        self.reclaim();
+    }
+
+    fn retire(&self, entry: *mut Entry<K, V>) {
+        let unlinked = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        self.push_retired(Box::into_raw(Box::new(Retired {
+            entry,
+            unlinked,
+            next: std::ptr::null_mut(),
+        })));
+    }
+
+    fn push_retired(&self, retired: *mut Retired<K, V>) {
+        loop {
+            let head = self.retired.load(Ordering::SeqCst);
+            unsafe { (*retired).next = head };
+            if self
+                .retired
+                .compare_exchange(head, retired, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                return;
+            }
+        }
+    }
+
+    /// free the retired entries whose unlink every pinned generation is at
+    /// or past, the readers that pinned them started after the unlink.
+    fn reclaim(&self) {
+        let mut retired = self.retired.swap(std::ptr::null_mut(), Ordering::SeqCst);
+        if retired.is_null() {
+            return;
+        }
+
+        let oldest = self.oldest_pin();
+        while !retired.is_null() {
+            let current = retired;
+            retired = unsafe { (*current).next };
+            if unsafe { (*current).unlinked } <= oldest {
+                let boxed = unsafe { Box::from_raw(current) };
+                drop(unsafe { Box::from_raw(boxed.entry) });
+            } else {
+                self.push_retired(current);
+            }
+        }
+    }
+
+    /// Pin the current generation for the calling reader.
+    fn pin(&self) -> Pin<'_> {
+        let mut generation = self.generation.load(Ordering::SeqCst);
+        let slot = self.claim_slot(generation);
+        // a thread looking for the oldest pin might have missed the slot
+        // while it was claimed, the generation is only safe to use when it did
+        // not move in the meantime.
+        loop {
+            let current = self.generation.load(Ordering::SeqCst);
+            if current == generation {
+                return Pin { slot, generation };
+            }
+            generation = current;
+            slot.generation.store(generation, Ordering::SeqCst);
+        }
+    }
+
+    fn claim_slot(&self, generation: u64) -> &ReaderSlot {
+        let mut current = self.readers.load(Ordering::SeqCst);
+        while let Some(slot) = unsafe { current.as_ref() } {
+            if slot
+                .generation
+                .compare_exchange(IDLE, generation, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                return slot;
+            }
+            current = slot.next;
+        }
+
+        let slot = Box::into_raw(Box::new(ReaderSlot {
+            generation: AtomicU64::new(generation),
+            next: std::ptr::null_mut(),
+        }));
+        loop {
+            let head = self.readers.load(Ordering::SeqCst);
+            unsafe { (*slot).next = head };
+            if self
+                .readers
+                .compare_exchange(head, slot, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                return unsafe { &*slot };
+            }
+        }
+    }
+
+    /// the oldest generation any reader pinned, or the current generation
+    /// when there are no readers.
+    fn oldest_pin(&self) -> u64 {
+        let mut oldest = self.generation.load(Ordering::SeqCst);
+        let mut current = self.readers.load(Ordering::SeqCst);
+        while let Some(slot) = unsafe { current.as_ref() } {
+            oldest = oldest.min(slot.generation.load(Ordering::SeqCst));
+            current = slot.next;
+        }
+        oldest
+    }
+
+    /// make sure the given generation field holds a generation. When it is
+    /// still pending, a fresh generation is assigned on behalf of the thread
+    /// that is about to do so.
+    ///
+    /// # Returns
+    ///
+    /// The generation stored in the field.
+    fn assign(&self, field: &AtomicU64) -> u64 {
+        let current = field.load(Ordering::SeqCst);
+        if current != PENDING {
+            return current;
+        }
+
+        let fresh = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        match field.compare_exchange(PENDING, fresh, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => fresh,
+            Err(assigned) => assigned,
+        }
+    }
+
+    fn visible(&self, entry: &Entry<K, V>, generation: u64) -> bool {
+        self.assign(&entry.inserted) <= generation && self.assign(&entry.removed) > generation
+    }
+}
+
+impl<K, V, S> Drop for LockFreeTable<K, V, S> {
+    fn drop(&mut self) {
+        for bucket in self.buckets.iter_mut() {
+            let mut current = *bucket.head.get_mut();
+            while !current.is_null() {
+                let mut entry = unsafe { Box::from_raw(current) };
+                current = *entry.next.get_mut();
+            }
+        }
+
+        let mut retired = *self.retired.get_mut();
+        while !retired.is_null() {
+            let boxed = unsafe { Box::from_raw(retired) };
+            drop(unsafe { Box::from_raw(boxed.entry) });
+            retired = boxed.next;
+        }
+
+        let mut slot = *self.readers.get_mut();
+        while !slot.is_null() {
+            let boxed = unsafe { Box::from_raw(slot) };
+            slot = boxed.next;
+        }
+    }
+}
+
+/// Generation pinned by a reader, entries the reader can reach are not freed
+/// while it exists.
+struct Pin<'a> {
+    slot: &'a ReaderSlot,
+    generation: u64,
+}
+
+impl Drop for Pin<'_> {
+    fn drop(&mut self) {
+        self.slot.generation.store(IDLE, Ordering::SeqCst);
+    }
+}
+
+/// Entry returned by [LockFreeTable::get] and [LockFreeTable::iter_snapshot].
+/// Dereferences to the value, the entry is not freed while the guard exists.
+pub struct EntryGuard<'a, K, V> {
+    _pin: Arc<Pin<'a>>,
+    entry: *const Entry<K, V>,
+}
+
+// a guard hands out shared references to its key and value.
+unsafe impl<K: Sync, V: Sync> Send for EntryGuard<'_, K, V> {}
+unsafe impl<K: Sync, V: Sync> Sync for EntryGuard<'_, K, V> {}
+
+impl<'a, K, V> EntryGuard<'a, K, V> {
+    pub fn key(&self) -> &K {
+        unsafe { &(*self.entry).key }
+    }
+}
+
+impl<'a, K, V> Deref for EntryGuard<'a, K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &(*self.entry).value }
+    }
+}
+
+/// Iterator returned by [LockFreeTable::iter_snapshot]. The generation of
+/// the snapshot stays pinned until the iterator and all entries it yielded
+/// are dropped.
+pub struct SnapshotIter<'a, K, V, S> {
+    table: &'a LockFreeTable<K, V, S>,
+    pin: Arc<Pin<'a>>,
+    bucket: usize,
+    current: *const Entry<K, V>,
+}
+
+// the iterator only reads the table and yields guards.
+unsafe impl<K: Send + Sync, V: Send + Sync, S: Sync> Send for SnapshotIter<'_, K, V, S> {}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher> Iterator for SnapshotIter<'a, K, V, S> {
+    type Item = EntryGuard<'a, K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            while self.current.is_null() {
+                let bucket = self.table.buckets.get(self.bucket)?;
+                self.current = bucket.head.load(Ordering::SeqCst);
+                self.bucket += 1;
+            }
+
+            let entry = unsafe { &*self.current };
+            self.current = entry.next.load(Ordering::SeqCst);
+            if self.table.visible(entry, self.pin.generation) {
+                return Some(EntryGuard {
+                    _pin: self.pin.clone(),
+                    entry,
+                });
+            }
+        }
+    }
+}
+
+#[test]
+fn insert_remove_test() {
+    let table = LockFreeTable::with_buckets(4);
+    assert!(table.insert("a", 1));
+    assert!(table.insert("b", 2));
+    assert!(!table.insert("a", 3));
+    assert_eq!(Some(1), table.get(&"a").map(|value| *value));
+
+    assert!(table.remove(&"a"));
+    assert!(!table.remove(&"a"));
+    assert!(table.get(&"a").is_none());
+
+    assert!(table.insert("a", 4));
+    assert_eq!(Some(4), table.get(&"a").map(|value| *value));
+}
+
+#[test]
+fn iter_snapshot_test() {
+    let table = LockFreeTable::with_buckets(8);
+    for i in 0..32usize {
+        table.insert(i, i * 10);
+    }
+
+    let snapshot = table.iter_snapshot();
+    for i in 0..16usize {
+        table.remove(&i);
+        table.insert(i + 100, 0);
+    }
+    table.insert(0, 1);
+
+    let mut seen: Vec<(usize, usize)> = snapshot.map(|entry| (*entry.key(), *entry)).collect();
+    seen.sort();
+    assert_eq!((0..32).map(|i| (i, i * 10)).collect::<Vec<_>>(), seen);
+
+    let mut keys: Vec<usize> = table.iter_snapshot().map(|entry| *entry.key()).collect();
+    keys.sort();
+    let expected: Vec<usize> = std::iter::once(0).chain(16..32).chain(100..116).collect();
+    assert_eq!(expected, keys);
+}
+
+#[test]
+fn snapshot_send_test() {
+    let table = LockFreeTable::with_buckets(8);
+    for i in 0..32usize {
+        table.insert(i, i * 10);
+    }
+
+    std::thread::scope(|scope| {
+        let snapshot = table.iter_snapshot();
+        let entry = table.get(&0).unwrap();
+        for i in 0..32usize {
+            table.remove(&i);
+        }
+
+        let seen = scope.spawn(move || {
+            assert_eq!(0, *entry);
+            snapshot.count()
+        });
+        assert_eq!(32, seen.join().unwrap());
+    });
+    assert_eq!(0, table.iter_snapshot().count());
+}
+
+#[test]
+fn remove_unlinks_test() {
+    let table = LockFreeTable::with_buckets(1);
+    let chain_len = |table: &LockFreeTable<usize, usize>| {
+        let mut len = 0;
+        let mut current = table.buckets[0].head.load(Ordering::SeqCst);
+        while let Some(entry) = unsafe { current.as_ref() } {
+            len += 1;
+            current = entry.next.load(Ordering::SeqCst);
+        }
+        len
+    };
+
+    table.insert(0, 0);
+    for i in 0..100 {
+        assert!(table.insert(1, i));
+        assert!(table.remove(&1));
+    }
+    assert_eq!(1, chain_len(&table));
+    assert!(table.retired.load(Ordering::SeqCst).is_null());
+
+    // the snapshot can still see the removed entry, the entry inserted and
+    // removed after it was taken cannot be unlinked before it either
+    let snapshot = table.iter_snapshot();
+    assert!(table.remove(&0));
+    assert!(table.insert(0, 1));
+    assert!(table.remove(&0));
+    assert_eq!(2, chain_len(&table));
+
+    let seen: Vec<(usize, usize)> = snapshot.map(|entry| (*entry.key(), *entry)).collect();
+    assert_eq!(vec![(0, 0)], seen);
+
+    assert!(table.insert(2, 2));
+    assert!(table.remove(&2));
+    assert_eq!(0, chain_len(&table));
+    assert!(table.retired.load(Ordering::SeqCst).is_null());
+}
+
+#[test]
+fn concurrent_snapshot_test() {
+    let table = std::sync::Arc::new(LockFreeTable::with_buckets(4));
+    let threads: Vec<_> = (0..4usize)
+        .map(|n| {
+            let table = table.clone();
+            std::thread::spawn(move || {
+                for i in 0..1000 {
+                    assert!(table.insert(n, i));
+                    assert!(table.remove(&n));
+                }
+            })
+        })
+        .collect();
+
+    for _ in 0..100 {
+        let mut keys: Vec<usize> = table.iter_snapshot().map(|entry| *entry.key()).collect();
+        let len = keys.len();
+        keys.sort();
+        keys.dedup();
+        assert_eq!(len, keys.len());
+    }
+    for handle in threads {
+        handle.join().unwrap();
+    }
+    assert_eq!(0, table.iter_snapshot().count());
+}
+
+#[cfg(loom)]
+#[test]
+fn test_insert_same_key() {
+    loom::model(|| {
+        const THREAD_N: usize = loom::MAX_THREADS - 1;
+        let table = loom::sync::Arc::new(LockFreeTable::with_buckets(1));
+        let threads: Vec<_> = (0..THREAD_N)
+            .map(|n| {
+                let clone = table.clone();
+                loom::thread::spawn(move || clone.insert(0usize, n))
+            })
+            .collect();
+
+        let inserted = threads
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .filter(|inserted| *inserted)
+            .count();
+        assert_eq!(1, inserted);
+        assert_eq!(1, table.iter_snapshot().count());
+    });
+}
@@ -0,0 +1,77 @@
+use crate::binarytree::LockFreeBinaryTree;
+use crate::treemap::LockFreeTreeMap;
+
+/// Single-threaded builder for a [LockFreeBinaryTree]. Every `push` on a
+/// shared tree pays for a search and a CAS and, because the tree does not
+/// rebalance itself, sorted input degrades it into a list. The builder
+/// collects all items first and links them median-first with plain stores,
+/// so the resulting tree is balanced and the root is published once.
+///
+/// This is the recommended way to construct a tree that is built once and
+/// afterwards only queried.
+///
+/// ```
+/// use lockfree::treebuilder::LockFreeTreeBuilder;
+///
+/// let tree = LockFreeTreeBuilder::new().add(3).add(1).add(2).build();
+/// assert_eq!(2, *tree.find(&2));
+/// ```
+pub struct LockFreeTreeBuilder<T> {
+    items: Vec<T>,
+}
+
+impl<T: Send + std::cmp::Ord> Default for LockFreeTreeBuilder<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Send + std::cmp::Ord> LockFreeTreeBuilder<T> {
+    pub fn new() -> Self {
+        Self { items: Vec::new() }
+    }
+
+    /// Add an item to the tree that is going to be built. Duplicates are
+    /// dropped when the tree is built.
+    #[allow(clippy::should_implement_trait)]
+    pub fn add(mut self, item: T) -> Self {
+        self.items.push(item);
+        self
+    }
+
+    /// Sort the collected items and build a balanced tree out of them. The
+    /// returned tree can be shared between threads right away.
+    pub fn build(mut self) -> LockFreeBinaryTree<T> {
+        self.items.sort();
+        self.items.dedup();
+
+        let items = self.items.into_iter().map(|item| (item, ())).collect();
+        LockFreeBinaryTree {
+            map: LockFreeTreeMap::from_sorted(items),
+        }
+    }
+}
+
+impl<T: Send + std::cmp::Ord> Extend<T> for LockFreeTreeBuilder<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.items.extend(iter);
+    }
+}
+
+#[test]
+fn build_test() {
+    let mut builder = LockFreeTreeBuilder::new();
+    builder.extend((0..100usize).rev());
+    let tree = builder.add(42).build();
+
+    let all: Vec<usize> = tree.range(..).map(|item| *item).collect();
+    assert_eq!((0..100).collect::<Vec<_>>(), all);
+    assert_eq!(50, *tree.find(&50));
+    tree.verify_invariants().unwrap();
+
+    #[cfg(debug_assertions)]
+    {
+        assert!(tree.is_balanced());
+        assert_eq!(7, tree.height());
+    }
+}
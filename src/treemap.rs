@@ -0,0 +1,1301 @@
+use std::ops::{Bound, Deref, RangeBounds};
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use crate::growablearray::LockFreeGrowableArray;
+
+/// Part of the [LockFreeTreeMap]. Used to build the binary tree with
+/// TODO: we need to have a thread-safe reference count somewhere to make sure
+/// that a thread cannot modify the pointers while others are reading them.
+pub(crate) struct Node<K, V> {
+    pub data: AtomicPtr<(K, V)>,
+    pub left: AtomicPtr<Node<K, V>>,
+    pub right: AtomicPtr<Node<K, V>>,
+    /// set for nodes attached by [LockFreeTreeMap::get_or_insert_with], whose
+    /// data stays null until their value is computed.
+    pending: Option<Box<Pending<K>>>,
+}
+
+/// Key of a node whose value is still being computed.
+struct Pending<K> {
+    key: K,
+    /// true while a thread is computing the value
+    claimed: AtomicBool,
+}
+
+impl<K, V> Node<K, V> {
+    pub fn leaky(data: *mut (K, V)) -> *mut Self {
+        let left = AtomicPtr::new(std::ptr::null_mut::<Node<K, V>>());
+        let right = AtomicPtr::new(std::ptr::null_mut::<Node<K, V>>());
+
+        let boxed = Box::from(Self {
+            data: AtomicPtr::new(data),
+            left,
+            right,
+            pending: None,
+        });
+
+        Box::into_raw(boxed)
+    }
+
+    /// Same as [Node::leaky], but for a node without data that is claimed by
+    /// the calling thread.
+    fn leaky_pending(key: K) -> *mut Self {
+        let node = Self::leaky(std::ptr::null_mut());
+        unsafe {
+            (*node).pending = Some(Box::new(Pending {
+                key,
+                claimed: AtomicBool::new(true),
+            }))
+        };
+        node
+    }
+
+    /// key of the node, also available while its data is still pending.
+    pub fn key(&self) -> &K {
+        match &self.pending {
+            Some(pending) => &pending.key,
+            None => unsafe { &(*self.data.load(Ordering::Acquire)).0 },
+        }
+    }
+}
+
+impl<K, V> Drop for Node<K, V> {
+    fn drop(&mut self) {
+        let data = *self.data.get_mut();
+        if !data.is_null() {
+            unsafe { drop(Box::from_raw(data)) };
+        }
+    }
+}
+
+/// Key value pair that got replaced. Concurrent readers might still hold a
+/// guard to it, so it is kept alive until the map is dropped.
+struct Retired<K, V> {
+    data: *mut (K, V),
+    next: *mut Retired<K, V>,
+}
+
+/// structure that guards the immutability lifetime of the contained data. As long an instance of
+/// this guard exists the data it points to is not freed.
+pub struct DerefGuard<'a, T> {
+    phantom: std::marker::PhantomData<&'a T>,
+    data: *const T,
+}
+
+impl<'a, T> DerefGuard<'a, T> {
+    pub(crate) fn new(data: *const T) -> Self {
+        Self {
+            phantom: std::marker::PhantomData,
+            data,
+        }
+    }
+}
+
+impl<'a, T> Deref for DerefGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.data }
+    }
+}
+
+/// Binary search tree that maps ordered keys to values. Nodes are only ever
+/// attached to empty child slots and never removed while the map is alive.
+/// The [crate::binarytree::LockFreeBinaryTree] is built on top of this map
+/// with `()` values.
+///
+/// The map is only `Sync` when its keys and values are, because every
+/// thread can get a shared reference to them:
+///
+/// ```compile_fail
+/// use lockfree::treemap::LockFreeTreeMap;
+///
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<LockFreeTreeMap<std::cell::RefCell<i32>, ()>>();
+/// ```
+///
+/// And only `Send` when its keys and values are:
+///
+/// ```compile_fail
+/// use lockfree::treemap::LockFreeTreeMap;
+///
+/// fn assert_send<T: Send>() {}
+/// assert_send::<LockFreeTreeMap<i32, std::rc::Rc<i32>>>();
+/// ```
+pub struct LockFreeTreeMap<K, V> {
+    pub(crate) head: AtomicPtr<Node<K, V>>,
+    retired: AtomicPtr<Retired<K, V>>,
+}
+
+// guards hand out shared references to keys and values on any thread and
+// `insert` drops or clones replaced pairs on the calling thread.
+unsafe impl<K: Send, V: Send> Send for LockFreeTreeMap<K, V> {}
+unsafe impl<K: Send + Sync, V: Send + Sync> Sync for LockFreeTreeMap<K, V> {}
+
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<LockFreeTreeMap<usize, String>>();
+    assert_send_sync::<crate::binarytree::LockFreeBinaryTree<usize>>();
+};
+
+impl<K: Send + std::cmp::Ord, V: Send> Default for LockFreeTreeMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Send + std::cmp::Ord, V: Send> LockFreeTreeMap<K, V> {
+    pub fn new() -> Self {
+        Self {
+            head: AtomicPtr::new(std::ptr::null_mut()),
+            retired: AtomicPtr::new(std::ptr::null_mut()),
+        }
+    }
+
+    /// Insert the given key value pair. When the key is already present its
+    /// pair is swapped for the new one with a single CAS. Readers that still
+    /// hold a guard to the replaced pair keep seeing the old value, the old
+    /// pair is freed when the map is dropped.
+    ///
+    /// # Returns
+    ///
+    /// A copy of the replaced value, or None when the key was not present.
+    pub fn insert(&self, key: K, value: V) -> Option<V>
+    where
+        V: Clone,
+    {
+        let data = Box::into_raw(Box::new((key, value)));
+        let node = self.attach(data, false)?;
+
+        let replaced = self.replace(unsafe { &*node }, data);
+        let value = unsafe { replaced.as_ref() }.map(|pair| pair.1.clone());
+        self.retire(replaced);
+        value
+    }
+
+    /// Insert the given key value pair, unless the key is already present.
+    ///
+    /// # Returns
+    ///
+    /// false when the key is already present, the given pair is dropped in
+    /// that case.
+    pub(crate) fn insert_if_absent(&self, key: K, value: V) -> bool {
+        let data = Box::into_raw(Box::new((key, value)));
+        match self.attach(data, false) {
+            None => true,
+            Some(node) if Self::publish(unsafe { &*node }, data).is_ok() => true,
+            Some(_) => {
+                unsafe { drop(Box::from_raw(data)) };
+                false
+            }
+        }
+    }
+
+    /// Get the value of the given key, or insert the value computed by `f`
+    /// when the key is absent. `f` is called at most once, even when multiple
+    /// threads race to insert the same key: the thread that attaches the node
+    /// computes the value, the others wait until it is published. Until then
+    /// the key looks absent to [LockFreeTreeMap::find] and the iterators.
+    ///
+    /// The key is cloned, the node needs its own copy for other threads to
+    /// compare against while the value is computed.
+    ///
+    /// # Panics
+    ///
+    /// The panic of `f` is propagated. The key stays absent, the next thread
+    /// waiting for it calls its own `f`.
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&self, key: K, f: F) -> DerefGuard<'_, V>
+    where
+        K: Clone,
+    {
+        self.get_or_insert_with_key(key, |_| f())
+    }
+
+    /// Same as [LockFreeTreeMap::get_or_insert_with], `f` gets the key it
+    /// computes the value for.
+    pub(crate) fn get_or_insert_with_key<F: FnOnce(&K) -> V>(
+        &self,
+        key: K,
+        f: F,
+    ) -> DerefGuard<'_, V>
+    where
+        K: Clone,
+    {
+        if let Some(pair) = self.find(&key) {
+            return DerefGuard::new(&pair.1);
+        }
+
+        let new_node = Node::leaky_pending(key.clone());
+        let node = match self.attach_node(new_node, false) {
+            None => unsafe { &*new_node },
+            Some(node) => {
+                let node = unsafe { &*node };
+                loop {
+                    if !node.data.load(Ordering::Acquire).is_null() {
+                        return self.value_of(node);
+                    }
+                    // the thread that claimed the node panicked, take over
+                    let unclaimed = node.pending.as_ref().is_some_and(|pending| {
+                        pending
+                            .claimed
+                            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                            .is_ok()
+                    });
+                    if unclaimed {
+                        break node;
+                    }
+                    std::thread::yield_now();
+                }
+            }
+        };
+
+        /// hands the claim back when `f` panics.
+        struct Unclaim<'a>(&'a AtomicBool);
+        impl Drop for Unclaim<'_> {
+            fn drop(&mut self) {
+                self.0.store(false, Ordering::Release);
+            }
+        }
+
+        let pending = node.pending.as_ref().expect("claimed node is pending");
+        let unclaim = Unclaim(&pending.claimed);
+        let value = f(&key);
+        std::mem::forget(unclaim);
+        let data = Box::into_raw(Box::new((key, value)));
+
+        // an insert might have published a pair in the meantime, keep that one
+        if Self::publish(node, data).is_err() {
+            unsafe { drop(Box::from_raw(data)) };
+        }
+        self.value_of(node)
+    }
+
+    /// Insert the given key value pair, even when the key is already present.
+    /// Equal keys are placed in the right subtree of each other, so an
+    /// in-order walk yields them in insertion order.
+    pub(crate) fn insert_allowing_duplicates(&self, key: K, value: V) {
+        let data = Box::into_raw(Box::new((key, value)));
+        let attached = self.attach(data, true);
+        debug_assert!(attached.is_none());
+    }
+
+    /// Get the entry of the given key to insert or modify its value in place.
+    /// The entry reflects the map at the moment it is looked up. Every
+    /// operation on it re-checks the tree, an insert by another thread in the
+    /// meantime is taken into account instead of being overwritten blindly.
+    ///
+    /// ```
+    /// use lockfree::treemap::LockFreeTreeMap;
+    ///
+    /// let counts = LockFreeTreeMap::new();
+    /// for word in ["a", "b", "a"] {
+    ///     counts.entry(word).and_modify(|count| count + 1).or_insert(1);
+    /// }
+    /// assert_eq!(2, counts.find(&"a").unwrap().1);
+    /// ```
+    pub fn entry(&self, key: K) -> Entry<'_, K, V> {
+        let head = self.head.load(Ordering::Acquire);
+        if !head.is_null() {
+            let (node, ordering) = self.relaxed_search(head, &key);
+            let pending = unsafe { (*node).data.load(Ordering::Acquire).is_null() };
+            if ordering == std::cmp::Ordering::Equal && !pending {
+                return Entry::Occupied(OccupiedEntry {
+                    map: self,
+                    node,
+                    key,
+                });
+            }
+        }
+        Entry::Vacant(VacantEntry {
+            map: self,
+            key,
+            modify: None,
+        })
+    }
+
+    /// Find the pair with the given key.
+    ///
+    /// # Returns
+    ///
+    /// A immutable reference to the found pair wrapped in a guard, or None
+    /// when the key is not present.
+    pub fn find<'a>(&'a self, key: &K) -> Option<DerefGuard<'a, (K, V)>> {
+        let (guard, ordering) = self.closest(key)?;
+        (ordering == std::cmp::Ordering::Equal).then_some(guard)
+    }
+
+    /// Find the pair with the given key, or the pair closest to it when the
+    /// key does not exist.
+    ///
+    /// # Returns
+    ///
+    /// The found pair together with the ordering of the given key compared
+    /// to it, or None when the map is empty or the closest key is still
+    /// pending, see [LockFreeTreeMap::get_or_insert_with].
+    pub(crate) fn closest<'a>(
+        &'a self,
+        key: &K,
+    ) -> Option<(DerefGuard<'a, (K, V)>, std::cmp::Ordering)> {
+        let head = self.head.load(Ordering::Acquire);
+        if head.is_null() {
+            return None;
+        }
+
+        let (node, ordering) = self.relaxed_search(head, key);
+        let data = unsafe { (*node).data.load(Ordering::Acquire) };
+        (!data.is_null()).then(|| (DerefGuard::new(data), ordering))
+    }
+
+    /// Iterate in ascending key order over all pairs whose key falls inside
+    /// the given range. Pairs inserted while iterating may or may not be
+    /// yielded.
+    ///
+    /// # Arguments
+    ///
+    /// * 'range'   bounds of the keys to yield
+    ///
+    /// # Returns
+    ///
+    /// A [RangeIter] yielding a guard for every pair inside the range.
+    pub fn range<'a, R: RangeBounds<K> + 'a>(&'a self, range: R) -> RangeIter<'a, K, V> {
+        self.range_by(move |key| {
+            let above_start = match range.start_bound() {
+                Bound::Included(start) => key >= start,
+                Bound::Excluded(start) => key > start,
+                Bound::Unbounded => true,
+            };
+            if !above_start {
+                return std::cmp::Ordering::Less;
+            }
+
+            let below_end = match range.end_bound() {
+                Bound::Included(end) => key <= end,
+                Bound::Excluded(end) => key < end,
+                Bound::Unbounded => true,
+            };
+            if !below_end {
+                return std::cmp::Ordering::Greater;
+            }
+
+            std::cmp::Ordering::Equal
+        })
+    }
+
+    /// Same as [LockFreeTreeMap::range], but the range is described by a
+    /// function that tells where a key lies relative to the range: `Less`
+    /// when it is below, `Equal` when it is inside and `Greater` when it is
+    /// above the range. The function must be monotonic over the ordering of
+    /// `K`.
+    pub(crate) fn range_by<'a, F>(&'a self, position: F) -> RangeIter<'a, K, V>
+    where
+        F: Fn(&K) -> std::cmp::Ordering + 'a,
+    {
+        let mut iter = RangeIter {
+            phantom: std::marker::PhantomData,
+            stack: Vec::new(),
+            position: Box::new(position),
+        };
+        iter.descend(self.head.load(Ordering::Acquire));
+        iter
+    }
+
+    /// Iterate over all values in ascending key order.
+    pub fn values_sorted(&self) -> impl Iterator<Item = DerefGuard<'_, V>> {
+        self.range(..).map(|pair| DerefGuard::new(&pair.1))
+    }
+
+    /// Iterate over all values in descending key order by walking the tree
+    /// right to left.
+    pub fn values_sorted_rev(&self) -> impl Iterator<Item = DerefGuard<'_, V>> {
+        let mut iter = RevIter {
+            phantom: std::marker::PhantomData,
+            stack: Vec::new(),
+        };
+        iter.descend(self.head.load(Ordering::Acquire));
+        iter.map(|pair| DerefGuard::new(&pair.1))
+    }
+
+    /// Group all pairs by the key computed by `grouper`. The values of every
+    /// group are stored in the order of their original keys. The result is a
+    /// freshly built, balanced map that can be shared between threads right
+    /// away. Pairs inserted while grouping may or may not be included.
+    pub fn group_by<G, F>(&self, grouper: F) -> LockFreeTreeMap<G, LockFreeGrowableArray<V>>
+    where
+        G: Send + std::cmp::Ord,
+        F: Fn(&K, &V) -> G,
+        V: Clone,
+    {
+        let mut groups: std::collections::BTreeMap<G, LockFreeGrowableArray<V>> =
+            std::collections::BTreeMap::new();
+        for pair in self.range(..) {
+            groups
+                .entry(grouper(&pair.0, &pair.1))
+                .or_default()
+                .push(pair.1.clone());
+        }
+
+        LockFreeTreeMap::from_sorted(groups.into_iter().collect())
+    }
+
+    /// Build a balanced map out of pairs that are sorted by key and contain
+    /// every key once. The map is not shared yet, so the nodes are linked
+    /// with plain stores instead of a search and a CAS per pair.
+    pub(crate) fn from_sorted(pairs: Vec<(K, V)>) -> Self {
+        debug_assert!(pairs.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        let mut pairs: Vec<Option<(K, V)>> = pairs.into_iter().map(Some).collect();
+        let map = Self::new();
+        map.head
+            .store(Self::link_median_first(&mut pairs), Ordering::Release);
+        map
+    }
+
+    /// Link the given pairs into a subtree rooted at their median.
+    fn link_median_first(pairs: &mut [Option<(K, V)>]) -> *mut Node<K, V> {
+        if pairs.is_empty() {
+            return std::ptr::null_mut();
+        }
+
+        let median = pairs.len() / 2;
+        let pair = pairs[median].take().expect("every pair is linked once");
+        let node = Node::leaky(Box::into_raw(Box::new(pair)));
+
+        let (left, right) = pairs.split_at_mut(median);
+        unsafe {
+            (*node).left = AtomicPtr::new(Self::link_median_first(left));
+            (*node).right = AtomicPtr::new(Self::link_median_first(&mut right[1..]));
+        }
+        node
+    }
+
+    /// Walk the whole tree and check that every node in the left subtree of
+    /// a node is less or equal, and every node in its right subtree greater
+    /// or equal, than the node itself, and that no node can be reached twice.
+    /// The bounds of all ancestors are carried down, so a node that ended up
+    /// on the wrong side of a grandparent is caught as well.
+    ///
+    /// This function reads the nodes without any protection and is meant to
+    /// be called from tests once all concurrent operations are done.
+    ///
+    /// # Returns
+    ///
+    /// The first violation that was found.
+    pub fn verify_invariants(&self) -> Result<(), InvariantViolation> {
+        let mut visited = std::collections::HashSet::new();
+        // every node together with the closest ancestor keys it must lie
+        // between
+        let head: *const Node<K, V> = self.head.load(Ordering::Acquire);
+        let mut nodes = vec![(head, None, None)];
+
+        while let Some((node, lower, upper)) = nodes.pop() {
+            if node.is_null() {
+                continue;
+            }
+
+            if !visited.insert(node) {
+                return Err(InvariantViolation {
+                    node_ptr: node as usize,
+                    expected: "node is reachable once",
+                    actual: "node is reachable more than once",
+                });
+            }
+
+            unsafe {
+                let key = (*node).key();
+                if lower.is_some_and(|lower| key < lower) {
+                    return Err(InvariantViolation {
+                        node_ptr: node as usize,
+                        expected: "right subtree >= ancestor",
+                        actual: "right subtree < ancestor",
+                    });
+                }
+
+                if upper.is_some_and(|upper| key > upper) {
+                    return Err(InvariantViolation {
+                        node_ptr: node as usize,
+                        expected: "left subtree <= ancestor",
+                        actual: "left subtree > ancestor",
+                    });
+                }
+
+                nodes.push(((*node).left.load(Ordering::Acquire), lower, Some(key)));
+                nodes.push(((*node).right.load(Ordering::Acquire), Some(key), upper));
+            }
+        }
+        Ok(())
+    }
+
+    /// Maximum amount of nodes on a path from the root to a leaf. Walks the
+    /// whole tree without protection, like [LockFreeTreeMap::verify_invariants].
+    #[cfg(debug_assertions)]
+    pub fn height(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        self.subtree_heights()
+            .get(&(head as *const _))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// true when the heights of the left and right subtree of every node
+    /// differ by at most one.
+    #[cfg(debug_assertions)]
+    pub fn is_balanced(&self) -> bool {
+        let heights = self.subtree_heights();
+        heights
+            .keys()
+            .all(|node| Self::balance_factor_of(&heights, *node).abs() <= 1)
+    }
+
+    /// Height of the left subtree minus the height of the right subtree of
+    /// the node holding the given key.
+    #[cfg(debug_assertions)]
+    pub fn balance_factor(&self, key: &K) -> Option<i64> {
+        let head = self.head.load(Ordering::Acquire);
+        if head.is_null() {
+            return None;
+        }
+
+        let (node, ordering) = self.relaxed_search(head, key);
+        if ordering != std::cmp::Ordering::Equal {
+            return None;
+        }
+        Some(Self::balance_factor_of(&self.subtree_heights(), node))
+    }
+
+    /// calculate the height of every subtree with an iterative post-order
+    /// walk, so degenerated trees do not overflow the stack.
+    #[cfg(debug_assertions)]
+    fn subtree_heights(&self) -> std::collections::HashMap<*const Node<K, V>, usize> {
+        let mut heights = std::collections::HashMap::new();
+        let mut nodes: Vec<(*const Node<K, V>, bool)> =
+            vec![(self.head.load(Ordering::Acquire), false)];
+
+        while let Some((node, children_done)) = nodes.pop() {
+            if node.is_null() {
+                continue;
+            }
+
+            let (left, right) = unsafe {
+                (
+                    (*node).left.load(Ordering::Acquire) as *const _,
+                    (*node).right.load(Ordering::Acquire) as *const _,
+                )
+            };
+            if children_done {
+                let left = heights.get(&left).copied().unwrap_or(0);
+                let right = heights.get(&right).copied().unwrap_or(0);
+                heights.insert(node, 1 + left.max(right));
+            } else {
+                nodes.push((node, true));
+                nodes.push((left, false));
+                nodes.push((right, false));
+            }
+        }
+        heights
+    }
+
+    #[cfg(debug_assertions)]
+    fn balance_factor_of(
+        heights: &std::collections::HashMap<*const Node<K, V>, usize>,
+        node: *const Node<K, V>,
+    ) -> i64 {
+        let height =
+            |child: *mut Node<K, V>| heights.get(&(child as *const _)).copied().unwrap_or(0) as i64;
+        unsafe {
+            height((*node).left.load(Ordering::Acquire))
+                - height((*node).right.load(Ordering::Acquire))
+        }
+    }
+
+    /// Attach a new node holding the given pair to the tree. A lost CAS means
+    /// another thread attached a node to that slot first and the search
+    /// continues from that node.
+    ///
+    /// # Arguments
+    ///
+    /// * 'data'        the pair to attach
+    /// * 'duplicates'  attach the pair to the right of an equal key instead of
+    ///   handing it back
+    ///
+    /// # Returns
+    ///
+    /// The node that already holds an equal key, in which case the given
+    /// data is not attached. None when the data got attached.
+    fn attach(&self, data: *mut (K, V), duplicates: bool) -> Option<*const Node<K, V>> {
+        self.attach_node(Node::leaky(data), duplicates)
+    }
+
+    /// Same as [LockFreeTreeMap::attach], for a node that is already
+    /// allocated. The node is freed when an equal key is found, its data is
+    /// handed back.
+    fn attach_node(
+        &self,
+        new_node: *mut Node<K, V>,
+        duplicates: bool,
+    ) -> Option<*const Node<K, V>> {
+        let key = unsafe { (*new_node).key() };
+
+        let mut current = self.head.load(Ordering::Acquire);
+        loop {
+            if current.is_null() {
+                match self.head.compare_exchange(
+                    current,
+                    new_node,
+                    Ordering::Release,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => return None,
+                    //other thread was quicker, proceed with adding it
+                    Err(head) => {
+                        current = head;
+                        continue;
+                    }
+                }
+            }
+
+            let (found, ordering) = self.relaxed_search(current, key);
+            let slot = unsafe {
+                match ordering {
+                    std::cmp::Ordering::Less => &(*found).left,
+                    std::cmp::Ordering::Greater => &(*found).right,
+                    std::cmp::Ordering::Equal if duplicates => &(*found).right,
+                    std::cmp::Ordering::Equal => {
+                        // hand the data back to the caller, only free the node
+                        (*new_node)
+                            .data
+                            .store(std::ptr::null_mut(), Ordering::Relaxed);
+                        drop(Box::from_raw(new_node));
+                        return Some(found);
+                    }
+                }
+            };
+
+            match slot.compare_exchange(
+                std::ptr::null_mut(),
+                new_node,
+                Ordering::Release,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return None,
+                Err(child) => current = child,
+            }
+        }
+    }
+
+    /// Replace the value of the given node by the value `f` computes from
+    /// it, stored together with the given key. When another thread replaced
+    /// the value first, `f` is called again with that value.
+    ///
+    /// # Returns
+    ///
+    /// The pair that got published.
+    fn modify(&self, node: *const Node<K, V>, key: K, f: &mut dyn FnMut(&V) -> V) -> *mut (K, V) {
+        let node = unsafe { &*node };
+        let mut current = node.data.load(Ordering::Acquire);
+        let data = Box::into_raw(Box::new((key, f(unsafe { &(*current).1 }))));
+        loop {
+            match node
+                .data
+                .compare_exchange(current, data, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => break,
+                Err(actual) => {
+                    current = actual;
+                    unsafe { (*data).1 = f(&(*current).1) };
+                }
+            }
+        }
+        self.retire(current);
+        data
+    }
+
+    /// store the given pair in a node whose value is still pending.
+    fn publish(node: &Node<K, V>, data: *mut (K, V)) -> Result<(), *mut (K, V)> {
+        node.data
+            .compare_exchange(
+                std::ptr::null_mut(),
+                data,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .map(|_| ())
+    }
+
+    fn value_of(&self, node: *const Node<K, V>) -> DerefGuard<'_, V> {
+        let data = unsafe { (*node).data.load(Ordering::Acquire) };
+        DerefGuard::new(unsafe { &(*data).1 })
+    }
+
+    /// swap the pair of the given node for the given one.
+    ///
+    /// # Returns
+    ///
+    /// The replaced pair, the caller is responsible for retiring it. Null
+    /// when the node was still pending.
+    fn replace(&self, node: &Node<K, V>, data: *mut (K, V)) -> *mut (K, V) {
+        let mut current = node.data.load(Ordering::Acquire);
+        loop {
+            match node
+                .data
+                .compare_exchange(current, data, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return current,
+                // another insert replaced the value first, replace that one
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    fn retire(&self, data: *mut (K, V)) {
+        // a pending node had no pair to replace
+        if data.is_null() {
+            return;
+        }
+
+        let retired = Box::into_raw(Box::new(Retired {
+            data,
+            next: self.retired.load(Ordering::Relaxed),
+        }));
+        loop {
+            let next = unsafe { (*retired).next };
+            match self.retired.compare_exchange_weak(
+                next,
+                retired,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(head) => unsafe { (*retired).next = head },
+            }
+        }
+    }
+
+    /// This function walks over the tree to find the node that is closest to the
+    /// given value. Note: The tree can get modified while we are walking over it.
+    ///
+    /// # Arguments
+    ///
+    /// * 'current' the root node to start searching with. Cannot be null!
+    /// * 'value' the value to find
+    ///
+    /// # Return
+    ///
+    /// Pointer to the value closest to the given value, together with the
+    /// ordering of the given value compared to that node.
+    fn relaxed_search(
+        &self,
+        mut current: *const Node<K, V>,
+        to_find: &K,
+    ) -> (*const Node<K, V>, std::cmp::Ordering) {
+        assert!(!current.is_null());
+
+        loop {
+            unsafe {
+                let ordering = to_find.cmp((*current).key());
+                let new_current = match ordering {
+                    std::cmp::Ordering::Less => (*current).left.load(Ordering::Acquire),
+                    std::cmp::Ordering::Greater => (*current).right.load(Ordering::Acquire),
+                    std::cmp::Ordering::Equal => std::ptr::null_mut(),
+                };
+
+                if new_current.is_null() {
+                    return (current, ordering);
+                }
+
+                current = new_current;
+            }
+        }
+    }
+}
+
+impl<K, V> Drop for LockFreeTreeMap<K, V> {
+    fn drop(&mut self) {
+        let mut nodes = vec![*self.head.get_mut()];
+        while let Some(node) = nodes.pop() {
+            if node.is_null() {
+                continue;
+            }
+
+            let mut node = unsafe { Box::from_raw(node) };
+            nodes.push(*node.left.get_mut());
+            nodes.push(*node.right.get_mut());
+        }
+
+        let mut retired = *self.retired.get_mut();
+        while !retired.is_null() {
+            let boxed = unsafe { Box::from_raw(retired) };
+            unsafe { drop(Box::from_raw(boxed.data)) };
+            retired = boxed.next;
+        }
+    }
+}
+
+/// Returned by [LockFreeTreeMap::verify_invariants] when the tree is
+/// corrupted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvariantViolation {
+    /// address of the node that violates the invariant
+    pub node_ptr: usize,
+    pub expected: &'static str,
+    pub actual: &'static str,
+}
+
+/// View into a single key of a [LockFreeTreeMap], returned by
+/// [LockFreeTreeMap::entry].
+pub enum Entry<'a, K, V> {
+    Occupied(OccupiedEntry<'a, K, V>),
+    Vacant(VacantEntry<'a, K, V>),
+}
+
+impl<'a, K: Send + std::cmp::Ord, V: Send> Entry<'a, K, V> {
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+
+    /// Insert the given value when the key is vacant. When another thread
+    /// inserted the key in the meantime, the entry turns out to be occupied:
+    /// the modification given to [Entry::and_modify] is applied to the value
+    /// of that thread, or its value is kept when there is none. The given
+    /// value is dropped in that case.
+    ///
+    /// # Returns
+    ///
+    /// A guard to the value stored in the map.
+    pub fn or_insert(self, default: V) -> DerefGuard<'a, V> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Same as [Entry::or_insert], but the value is only computed when the
+    /// key is vacant.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> DerefGuard<'a, V> {
+        match self {
+            Entry::Occupied(entry) => entry.get(),
+            Entry::Vacant(VacantEntry { map, key, modify }) => {
+                let data = Box::into_raw(Box::new((key, default())));
+                match map.attach(data, false) {
+                    None => DerefGuard::new(unsafe { &(*data).1 }),
+                    Some(node) if LockFreeTreeMap::publish(unsafe { &*node }, data).is_ok() => {
+                        DerefGuard::new(unsafe { &(*data).1 })
+                    }
+                    Some(node) => {
+                        let (key, _) = *unsafe { Box::from_raw(data) };
+                        match modify {
+                            Some(mut f) => {
+                                let data = map.modify(node, key, &mut *f);
+                                DerefGuard::new(unsafe { &(*data).1 })
+                            }
+                            None => map.value_of(node),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Replace the value of an occupied key by the value `f` computes from
+    /// it. The new value is published with a CAS, when another thread
+    /// replaced the value first, `f` is called again with that value. The
+    /// key is cloned into the new pair.
+    ///
+    /// A vacant entry keeps `f`. When another thread inserts the key before
+    /// [Entry::or_insert] gets to it, `f` is applied to that value instead,
+    /// so `entry(key).and_modify(f).or_insert(value)` never loses an update.
+    pub fn and_modify<F: FnMut(&V) -> V + 'a>(self, mut f: F) -> Self
+    where
+        K: Clone,
+    {
+        match self {
+            Entry::Occupied(entry) => {
+                entry.map.modify(entry.node, entry.key.clone(), &mut f);
+                Entry::Occupied(entry)
+            }
+            Entry::Vacant(mut entry) => {
+                entry.modify = Some(match entry.modify.take() {
+                    Some(mut previous) => Box::new(move |value: &V| f(&previous(value))),
+                    None => Box::new(f),
+                });
+                Entry::Vacant(entry)
+            }
+        }
+    }
+}
+
+/// Key that is present in the map.
+pub struct OccupiedEntry<'a, K, V> {
+    map: &'a LockFreeTreeMap<K, V>,
+    node: *const Node<K, V>,
+    key: K,
+}
+
+impl<'a, K: Send + std::cmp::Ord, V: Send> OccupiedEntry<'a, K, V> {
+    /// A guard to the current value of the key.
+    pub fn get(&self) -> DerefGuard<'a, V> {
+        self.map.value_of(self.node)
+    }
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Replace the value of the key, see [LockFreeTreeMap::insert].
+    ///
+    /// # Returns
+    ///
+    /// A copy of the replaced value.
+    pub fn insert(self, value: V) -> V
+    where
+        V: Clone,
+    {
+        let data = Box::into_raw(Box::new((self.key, value)));
+        let replaced = self.map.replace(unsafe { &*self.node }, data);
+        let value = unsafe { (*replaced).1.clone() };
+        self.map.retire(replaced);
+        value
+    }
+}
+
+/// Modification kept by a [VacantEntry], see [Entry::and_modify].
+type Modify<'a, V> = Box<dyn FnMut(&V) -> V + 'a>;
+
+/// Key that is not present in the map.
+pub struct VacantEntry<'a, K, V> {
+    map: &'a LockFreeTreeMap<K, V>,
+    key: K,
+    /// modification to apply when the key turns out to be occupied
+    modify: Option<Modify<'a, V>>,
+}
+
+impl<'a, K: Send + std::cmp::Ord, V: Send> VacantEntry<'a, K, V> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Attach the key with the given value. When another thread inserted the
+    /// key in the meantime, its value is replaced like
+    /// [LockFreeTreeMap::insert] does.
+    ///
+    /// # Returns
+    ///
+    /// A guard to the inserted value.
+    pub fn insert(self, value: V) -> DerefGuard<'a, V> {
+        let data = Box::into_raw(Box::new((self.key, value)));
+        if let Some(node) = self.map.attach(data, false) {
+            let replaced = self.map.replace(unsafe { &*node }, data);
+            self.map.retire(replaced);
+        }
+        DerefGuard::new(unsafe { &(*data).1 })
+    }
+}
+
+/// Iterator returned by [LockFreeTreeMap::range]. Walks the tree in order
+/// and keeps the path of nodes that still need to be visited on a stack.
+pub struct RangeIter<'a, K, V> {
+    phantom: std::marker::PhantomData<&'a (K, V)>,
+    stack: Vec<*const Node<K, V>>,
+    position: Box<dyn Fn(&K) -> std::cmp::Ordering + 'a>,
+}
+
+impl<'a, K, V> RangeIter<'a, K, V> {
+    /// push the left spine of the given subtree onto the stack, skipping every
+    /// node that lies below the range together with its left subtree.
+    fn descend(&mut self, mut current: *const Node<K, V>) {
+        while !current.is_null() {
+            unsafe {
+                if (self.position)((*current).key()) == std::cmp::Ordering::Less {
+                    current = (*current).right.load(Ordering::Acquire);
+                } else {
+                    self.stack.push(current);
+                    current = (*current).left.load(Ordering::Acquire);
+                }
+            }
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for RangeIter<'a, K, V> {
+    type Item = DerefGuard<'a, (K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let node = self.stack.pop()?;
+            unsafe {
+                if (self.position)((*node).key()) == std::cmp::Ordering::Greater {
+                    self.stack.clear();
+                    return None;
+                }
+
+                self.descend((*node).right.load(Ordering::Acquire));
+                // skip nodes whose value is still pending
+                let data = (*node).data.load(Ordering::Acquire);
+                if !data.is_null() {
+                    return Some(DerefGuard::new(data));
+                }
+            }
+        }
+    }
+}
+
+/// Iterator over all pairs in descending key order.
+struct RevIter<'a, K, V> {
+    phantom: std::marker::PhantomData<&'a (K, V)>,
+    stack: Vec<*const Node<K, V>>,
+}
+
+impl<'a, K, V> RevIter<'a, K, V> {
+    /// push the right spine of the given subtree onto the stack.
+    fn descend(&mut self, mut current: *const Node<K, V>) {
+        while !current.is_null() {
+            self.stack.push(current);
+            current = unsafe { (*current).right.load(Ordering::Acquire) };
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for RevIter<'a, K, V> {
+    type Item = DerefGuard<'a, (K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let node = self.stack.pop()?;
+            unsafe {
+                self.descend((*node).left.load(Ordering::Acquire));
+                let data = (*node).data.load(Ordering::Acquire);
+                if !data.is_null() {
+                    return Some(DerefGuard::new(data));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn insert_find_test() {
+    let map = LockFreeTreeMap::new();
+    assert_eq!(None, map.insert(2, "two"));
+    assert_eq!(None, map.insert(1, "one"));
+    assert_eq!(None, map.insert(3, "three"));
+
+    let old = map.find(&2).unwrap();
+    assert_eq!(Some("two"), map.insert(2, "TWO"));
+    assert_eq!("two", old.1);
+    assert_eq!("TWO", map.find(&2).unwrap().1);
+    assert!(map.find(&4).is_none());
+
+    let values: Vec<&str> = map.range(2..).map(|pair| pair.1).collect();
+    assert_eq!(vec!["TWO", "three"], values);
+    map.verify_invariants().unwrap();
+}
+
+#[test]
+fn verify_invariants_test() {
+    let map = LockFreeTreeMap::<usize, ()>::new();
+    for i in [50, 20, 70] {
+        map.insert(i, ());
+    }
+    map.verify_invariants().unwrap();
+
+    unsafe {
+        let head = map.head.load(Ordering::Relaxed);
+        let left = (*head).left.load(Ordering::Relaxed);
+        let right = (*head).right.swap(left, Ordering::Relaxed);
+
+        let violation = map.verify_invariants().unwrap_err();
+        assert_eq!(left as usize, violation.node_ptr);
+        assert_eq!("right subtree >= ancestor", violation.expected);
+
+        (*head).right.store(head, Ordering::Relaxed);
+        let violation = map.verify_invariants().unwrap_err();
+        assert_eq!(head as usize, violation.node_ptr);
+
+        (*head).right.store(right, Ordering::Relaxed);
+    }
+    map.verify_invariants().unwrap();
+}
+
+#[test]
+fn verify_invariants_grandchild_test() {
+    let map = LockFreeTreeMap::<usize, ()>::new();
+    for i in [50, 20, 70, 60] {
+        map.insert(i, ());
+    }
+    map.verify_invariants().unwrap();
+
+    // 60 moves from the left of 70 to the right of 20, it is greater than its
+    // new parent but not less than the root.
+    unsafe {
+        let head = map.head.load(Ordering::Relaxed);
+        let twenty = (*head).left.load(Ordering::Relaxed);
+        let seventy = (*head).right.load(Ordering::Relaxed);
+        let sixty = (*seventy)
+            .left
+            .swap(std::ptr::null_mut(), Ordering::Relaxed);
+        (*twenty).right.store(sixty, Ordering::Relaxed);
+
+        let violation = map.verify_invariants().unwrap_err();
+        assert_eq!(sixty as usize, violation.node_ptr);
+        assert_eq!("left subtree <= ancestor", violation.expected);
+
+        (*twenty)
+            .right
+            .store(std::ptr::null_mut(), Ordering::Relaxed);
+        (*seventy).left.store(sixty, Ordering::Relaxed);
+    }
+    map.verify_invariants().unwrap();
+}
+
+#[test]
+fn values_sorted_test() {
+    let map = LockFreeTreeMap::new();
+    for (time, event) in [(30, "c"), (10, "a"), (50, "e"), (20, "b"), (40, "d")] {
+        map.insert(time, event);
+    }
+
+    let values: Vec<&str> = map.values_sorted().map(|value| *value).collect();
+    assert_eq!(vec!["a", "b", "c", "d", "e"], values);
+
+    let values: Vec<&str> = map.values_sorted_rev().map(|value| *value).collect();
+    assert_eq!(vec!["e", "d", "c", "b", "a"], values);
+    map.verify_invariants().unwrap();
+}
+
+#[test]
+fn group_by_test() {
+    let map = LockFreeTreeMap::new();
+    for (key, value) in [(4, "d"), (1, "a"), (6, "f"), (3, "c"), (2, "b"), (5, "e")] {
+        map.insert(key, value);
+    }
+
+    let grouped = map.group_by(|key, _| key % 2 == 0);
+    let odd: Vec<&str> = grouped.find(&false).unwrap().1.iter().copied().collect();
+    let even: Vec<&str> = grouped.find(&true).unwrap().1.iter().copied().collect();
+    assert_eq!(vec!["a", "c", "e"], odd);
+    assert_eq!(vec!["b", "d", "f"], even);
+    grouped.verify_invariants().unwrap();
+}
+
+#[test]
+fn entry_test() {
+    let map = LockFreeTreeMap::new();
+    assert_eq!(1, *map.entry("a").or_insert(1));
+    assert_eq!(1, *map.entry("a").or_insert(2));
+    assert_eq!(11, *map.entry("a").and_modify(|v| v + 10).or_insert(0));
+
+    match map.entry("b") {
+        Entry::Vacant(entry) => assert_eq!(2, *entry.insert(2)),
+        Entry::Occupied(_) => panic!("b is not present"),
+    }
+    match map.entry("b") {
+        Entry::Occupied(entry) => assert_eq!(2, entry.insert(3)),
+        Entry::Vacant(_) => panic!("b is present"),
+    }
+    assert_eq!(3, map.find(&"b").unwrap().1);
+
+    // the key gets inserted between looking up the entry and using it
+    let vacant = map.entry("c");
+    assert_eq!(&"c", vacant.key());
+    map.insert("c", 4);
+    assert_eq!(4, *vacant.or_insert(5));
+    map.verify_invariants().unwrap();
+}
+
+#[test]
+fn entry_and_modify_concurrent_test() {
+    let map = std::sync::Arc::new(LockFreeTreeMap::new());
+    for i in 0..10usize {
+        map.entry(i).or_insert(0);
+    }
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let map = map.clone();
+            std::thread::spawn(move || {
+                for i in 0..1000usize {
+                    map.entry(i % 10).and_modify(|count| count + 1).or_insert(1);
+                }
+            })
+        })
+        .collect();
+    for handle in threads {
+        handle.join().unwrap();
+    }
+
+    assert!(map.values_sorted().all(|count| *count == 400));
+    map.verify_invariants().unwrap();
+}
+
+#[test]
+fn entry_and_modify_vacant_concurrent_test() {
+    for _ in 0..20 {
+        let map = std::sync::Arc::new(LockFreeTreeMap::new());
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let map = map.clone();
+                std::thread::spawn(move || {
+                    for i in 0..200usize {
+                        map.entry(i).and_modify(|count| count + 1).or_insert(1);
+                    }
+                })
+            })
+            .collect();
+        for handle in threads {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(200, map.range(..).count());
+        assert!(map.values_sorted().all(|count| *count == 8));
+        map.verify_invariants().unwrap();
+    }
+}
+
+#[test]
+fn get_or_insert_with_test() {
+    use std::sync::atomic::AtomicUsize;
+
+    let map = std::sync::Arc::new(LockFreeTreeMap::new());
+    let calls = std::sync::Arc::new(AtomicUsize::new(0));
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            let map = map.clone();
+            let calls = calls.clone();
+            std::thread::spawn(move || {
+                let value = map.get_or_insert_with(1, || {
+                    calls.fetch_add(1, Ordering::Relaxed);
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                    "one".to_string()
+                });
+                assert_eq!("one", *value);
+            })
+        })
+        .collect();
+    for handle in threads {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(1, calls.load(Ordering::Relaxed));
+    assert_eq!("one", *map.get_or_insert_with(1, || unreachable!()));
+    map.verify_invariants().unwrap();
+}
+
+#[test]
+fn get_or_insert_with_panic_test() {
+    let map = LockFreeTreeMap::new();
+    map.insert(2, 2);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        map.get_or_insert_with(1, || panic!("no value"));
+    }));
+    assert!(result.is_err());
+
+    // the key stays absent until a value gets published
+    assert!(map.find(&1).is_none());
+    assert_eq!(1, map.range(..).count());
+    assert!(matches!(map.entry(1), Entry::Vacant(_)));
+
+    assert_eq!(1, *map.get_or_insert_with(1, || 1));
+    assert_eq!(Some(1), map.insert(1, 10));
+    assert_eq!(
+        vec![10, 2],
+        map.values_sorted().map(|v| *v).collect::<Vec<_>>()
+    );
+    map.verify_invariants().unwrap();
+}